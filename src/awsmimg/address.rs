@@ -0,0 +1,97 @@
+use std::io;
+use std::io::{Read, Seek};
+
+use awsmimg::error::{Error, Result};
+
+/// Where the cartridge ROM appears on the AGB bus.
+pub const ROM_BASE: u64 = 0x0800_0000;
+
+/// How much of the bus each view of the cartridge ROM covers.
+const ROM_WINDOW: u64 = 0x0200_0000;
+
+/// The cartridge ROM is visible three times over, once for each set of wait
+/// states, and nothing past that is ROM.
+const ROM_END: u64 = ROM_BASE + ROM_WINDOW * 3;
+
+/// Translate an AGB bus address in any of the cartridge ROM's mirrors into an
+/// offset within the ROM file.
+pub fn rom_offset(address: u64) -> Option<u64> {
+    match (ROM_BASE..ROM_END).contains(&address) {
+        true => Some((address - ROM_BASE) % ROM_WINDOW),
+        false => None
+    }
+}
+
+/// Parse a number given either in decimal or as 0x-prefixed hex.
+pub fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    
+    match value.starts_with("0x") || value.starts_with("0X") {
+        true => u64::from_str_radix(&value[2..], 16).ok(),
+        false => value.parse().ok()
+    }
+}
+
+/// Parse an offset into a file, given either in decimal or as 0x-prefixed hex.
+pub fn parse_offset(value: &str) -> Result<u64> {
+    match parse_number(value) {
+        Some(number) => Ok(number),
+        None => Err(Error::invalid(format!("{} is not a decimal or 0x-prefixed hex offset", value.trim())))
+    }
+}
+
+/// Parse an offset into a ROM file like parse_offset, additionally accepting
+/// AGB cartridge bus addresses.
+///
+/// Values from 0x08000000 up to 0x0DFFFFFF are taken to be bus addresses, as
+/// a debugger or disassembly would show them, and translated to file offsets.
+/// Offsets that large into other files cannot be given this way.
+pub fn parse_rom_offset(value: &str) -> Result<u64> {
+    let number = parse_offset(value)?;
+    
+    Ok(rom_offset(number).unwrap_or(number))
+}
+
+/// Read the little-endian pointer stored at the given offset of a ROM file and
+/// return the file offset it points to.
+///
+/// Pointers to anything other than the cartridge ROM, such as work RAM, do
+/// not point into the file and are an error.
+pub fn follow_pointer<R>(file: &mut R, at: u64) -> Result<u64> where R: Read + Seek {
+    let mut bytes = [0u8; 4];
+    
+    file.seek(io::SeekFrom::Start(at))?;
+    file.read_exact(&mut bytes)?;
+    
+    let address = u32::from_le_bytes(bytes) as u64;
+    match rom_offset(address) {
+        Some(offset) => Ok(offset),
+        None => Err(Error::invalid(format!("The pointer at 0x{:X} holds 0x{:08X}, which is not a cartridge ROM address", at, address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use awsmimg::address::{parse_offset, parse_rom_offset, follow_pointer};
+    
+    #[test]
+    fn offsets_and_pointers() {
+        assert_eq!(parse_offset("1234").unwrap(), 1234);
+        assert_eq!(parse_offset("0x1F00").unwrap(), 0x1F00);
+        assert_eq!(parse_offset("0x08123456").unwrap(), 0x08123456);
+        assert_eq!(parse_rom_offset("0x08123456").unwrap(), 0x123456);
+        assert_eq!(parse_rom_offset("0x09FFFFFF").unwrap(), 0x1FFFFFF);
+        assert_eq!(parse_rom_offset("0x0A000010").unwrap(), 0x10);
+        assert_eq!(parse_rom_offset("0x0D000010").unwrap(), 0x1000010);
+        assert_eq!(parse_rom_offset("0x0E000000").unwrap(), 0x0E000000);
+        assert_eq!(parse_rom_offset("4096").unwrap(), 4096);
+        assert!(parse_offset("0x").is_err());
+        assert!(parse_offset("12k").is_err());
+        
+        let mut rom = Cursor::new(vec![0, 0, 0, 0, 0x10, 0x32, 0x54, 0x08, 0x00, 0x01, 0x00, 0x02]);
+        assert_eq!(follow_pointer(&mut rom, 4).unwrap(), 0x543210);
+        assert!(follow_pointer(&mut rom, 8).is_err());
+        assert!(follow_pointer(&mut rom, 10).is_err());
+    }
+}
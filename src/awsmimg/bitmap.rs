@@ -0,0 +1,213 @@
+use std::io;
+use std::io::{Cursor, Read, Write};
+use image::{GenericImage, ImageBuffer, Pixel, Primitive, Rgba};
+
+use awsmimg::error::{Error, Result};
+use awsmimg::decoder::{IndexedGraphicsDecoder, DirectGraphicsDecoder};
+use awsmimg::encoder::{IndexedGraphicsEncoder, DirectGraphicsEncoder, IndexSource, indexes_from_image};
+use awsmimg::formats::agb::{AGB8Encoder, AGB16Encoder};
+
+/// Where the second page of a page-flipped bitmap mode starts.
+pub const PAGE_OFFSET: usize = 0xA000;
+
+/// The AGB's framebuffer background modes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BitmapMode {
+    Mode3, //240x160, 16bpp direct color, one page
+    Mode4, //240x160, 8bpp indexed color, two pages
+    Mode5  //160x128, 16bpp direct color, two pages
+}
+
+pub fn interpret_bitmap_mode_name(name: &str) -> Option<BitmapMode> {
+    let name = name.to_ascii_lowercase();
+    
+    match name.as_ref() {
+        "mode3" => Some(BitmapMode::Mode3),
+        "mode4" => Some(BitmapMode::Mode4),
+        "mode5" => Some(BitmapMode::Mode5),
+        _ => None
+    }
+}
+
+impl BitmapMode {
+    /// The size of a single frame, in pixels.
+    pub fn frame_size(&self) -> (u32, u32) {
+        match *self {
+            BitmapMode::Mode3 | BitmapMode::Mode4 => (240, 160),
+            BitmapMode::Mode5 => (160, 128)
+        }
+    }
+    
+    /// The number of frames the mode can page flip between.
+    pub fn pages(&self) -> u32 {
+        match *self {
+            BitmapMode::Mode3 => 1,
+            _ => 2
+        }
+    }
+    
+    pub fn is_indexed(&self) -> bool {
+        *self == BitmapMode::Mode4
+    }
+    
+    /// The size of a single frame, in bytes.
+    pub fn frame_bytes(&self) -> usize {
+        let (w, h) = self.frame_size();
+        
+        match self.is_indexed() {
+            true => (w * h) as usize,
+            false => (w * h * 2) as usize
+        }
+    }
+    
+    /// Determine how many frames an image holds, given that frames are
+    /// stacked vertically. Yields None if the image is not exactly one frame
+    /// wide and one or (for page-flipped modes) two frames tall.
+    pub fn frame_count(&self, image_size: (u32, u32)) -> Option<u32> {
+        let (fw, fh) = self.frame_size();
+        
+        match image_size {
+            (w, h) if w == fw && h == fh => Some(1),
+            (w, h) if w == fw && h == fh * 2 && self.pages() == 2 => Some(2),
+            _ => None
+        }
+    }
+}
+
+/// Given an image holding one or two frames stacked vertically, encode them as
+/// the framebuffer of the given bitmap mode.
+/// 
+/// A second frame is written to the second page at 0xA000, with the gap
+/// between the pages filled with zeroes. source determines how mode 4 frames
+/// are converted to indexes; direct color modes ignore it. Any palette
+/// generated for the image is returned so that it may be encoded separately.
+pub fn encode_image_as_bitmap<'a, 'p, W, I, P, S>(mode: BitmapMode, w: &mut W, image: &I, source: IndexSource<'p>) -> Result<Option<Vec<Rgba<u8>>>> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, W: Write + 'a {
+    let (fw, fh) = mode.frame_size();
+    let frames = match mode.frame_count(image.dimensions()) {
+        Some(frames) => frames,
+        None => return Err(Error::invalid(match mode.pages() {
+            1 => format!("Image must be {}x{} for this bitmap mode", fw, fh),
+            _ => format!("Image must be {}x{}, or {}x{} for two pages, for this bitmap mode", fw, fh, fw, fh * 2)
+        }))
+    };
+    
+    let mut out = Vec::with_capacity(PAGE_OFFSET * frames as usize);
+    let mut palette = None;
+    
+    if mode.is_indexed() {
+        //Both pages share one palette, so convert them together.
+        let mut enc = AGB8Encoder::new_chunky(&mut out);
+        let data = indexes_from_image(&enc, image, source)?;
+        let mut indexes = data.indexes;
+        indexes.resize((fw * fh * frames) as usize, 0);
+        
+        enc.encode_indexes(indexes, fw, fh * frames)?;
+        palette = data.palette;
+    } else {
+        let mut enc = AGB16Encoder::new_agb(&mut out);
+        enc.encode_colors(image)?;
+    }
+    
+    if frames == 2 {
+        let second = out.split_off(mode.frame_bytes());
+        out.resize(PAGE_OFFSET, 0);
+        out.extend(second);
+    }
+    
+    w.write_all(&out)?;
+    
+    Ok(palette)
+}
+
+/// Given a framebuffer dump, decode each page it holds into frames stacked
+/// vertically.
+/// 
+/// A dump reaching into the second page of a page-flipped mode yields both
+/// frames. Mode 4 frames are colored with the palette if given, or with
+/// indexes represented as grayscale values otherwise. As the backdrop shows
+/// through index 0 of a mode 4 framebuffer, it is drawn opaque.
+pub fn decode_bitmap_as_image<'a, R>(mode: BitmapMode, r: &mut R, size: usize, palette: Option<&[Rgba<u8>]>) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where R: Read + 'a {
+    let mut data = Vec::new();
+    r.take(size as u64).read_to_end(&mut data)?;
+    
+    if data.len() < mode.frame_bytes() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than a single frame of this bitmap mode").into());
+    }
+    
+    let frames = match mode.pages() == 2 && data.len() >= PAGE_OFFSET + mode.frame_bytes() {
+        true => 2,
+        false => 1
+    };
+    let (fw, fh) = mode.frame_size();
+    let mut out = ImageBuffer::new(fw, fh * frames);
+    
+    for page in 0..frames {
+        let start = page as usize * PAGE_OFFSET;
+        let mut page_data = Cursor::new(&data[start..start + mode.frame_bytes()]);
+        
+        let frame = match mode.is_indexed() {
+            true => {
+                let indexes : Vec<u8> = AGB8Encoder::new_chunky(&mut page_data).decode_indexes(mode.frame_bytes())?;
+                
+                ImageBuffer::from_fn(fw, fh, |x, y| {
+                    let index = indexes[(y * fw + x) as usize];
+                    match palette {
+                        Some(pal) => pal.get(index as usize).map_or(Rgba([0, 0, 0, 255]), |c| Rgba([c[0], c[1], c[2], 255])),
+                        None => Rgba([index, index, index, 255])
+                    }
+                })
+            },
+            false => AGB16Encoder::new_agb(&mut page_data).decode_colors(fw, fh)?
+        };
+        
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            out.put_pixel(x, y + page * fh, *pixel);
+        }
+    }
+    
+    Ok(Box::new(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::bitmap::{BitmapMode, PAGE_OFFSET, encode_image_as_bitmap, decode_bitmap_as_image};
+    use awsmimg::encoder::IndexSource;
+    
+    #[test]
+    fn mode5_pages() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(160, 256, |x, y| {
+            Rgba([(x as u8) & 0xF8, (y as u8) & 0xF8, if y < 128 { 0 } else { 0xF8 }, 255])
+        });
+        let mut out = Cursor::new(Vec::new());
+        
+        encode_image_as_bitmap(BitmapMode::Mode5, &mut out, &img, IndexSource::Luma).unwrap();
+        assert_eq!(out.get_ref().len(), 0x14000);
+        
+        out.set_position(0);
+        let decoded = decode_bitmap_as_image(BitmapMode::Mode5, &mut out, 0x14000, None).unwrap();
+        assert_eq!(decoded.dimensions(), (160, 256));
+        assert_eq!(*decoded.get_pixel(16, 200), Rgba([16u8, 206, 0xFF, 255]));
+        
+        assert!(encode_image_as_bitmap(BitmapMode::Mode3, &mut out, &img, IndexSource::Luma).is_err());
+    }
+    
+    #[test]
+    fn mode4_pages() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(240, 320, |x, y| {
+            let l = ((x + y) % 256) as u8;
+            Rgba([l, l, l, 255])
+        });
+        let mut out = Cursor::new(Vec::new());
+        
+        encode_image_as_bitmap(BitmapMode::Mode4, &mut out, &img, IndexSource::Luma).unwrap();
+        assert_eq!(out.get_ref().len(), PAGE_OFFSET + 240 * 160);
+        assert_eq!(out.get_ref()[PAGE_OFFSET + 1], 161);
+        
+        out.set_position(0);
+        let decoded = decode_bitmap_as_image(BitmapMode::Mode4, &mut out, usize::MAX, None).unwrap();
+        assert_eq!(decoded.dimensions(), (240, 320));
+        assert_eq!(*decoded.get_pixel(1, 160), Rgba([161u8, 161, 161, 255]));
+    }
+}
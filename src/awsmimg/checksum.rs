@@ -0,0 +1,185 @@
+use std::fmt;
+
+use awsmimg::error::{Error, Result};
+
+/// Systems whose ROM headers carry checksums that flash carts, emulators, or
+/// the hardware itself check.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RomKind {
+    Gba, //AGB: one complement byte over the header
+    Gb,  //DMG/CGB: header checksum plus a 16-bit sum of the whole ROM
+    Nds  //NTR: CRC16 of the header
+}
+
+pub fn interpret_rom_kind_name(kind_given: &str) -> Option<RomKind> {
+    let kind = kind_given.to_ascii_lowercase();
+    
+    match kind.as_ref() {
+        "gba" | "agb" => Some(RomKind::Gba),
+        "gb" | "gbc" | "dmg" | "cgb" => Some(RomKind::Gb),
+        "nds" | "ntr" => Some(RomKind::Nds),
+        _ => None
+    }
+}
+
+/// One checksum in a ROM, as stored and as it should be.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Checksum {
+    pub name: &'static str,
+    
+    /// Where the checksum is stored within the ROM.
+    pub offset: usize,
+    
+    /// How many bytes the checksum takes up, which is also how many hex
+    /// digits' worth of it are worth showing.
+    pub size: usize,
+    
+    pub stored: u32,
+    pub computed: u32
+}
+
+impl Checksum {
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.computed
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.size * 2;
+        
+        match self.is_valid() {
+            true => write!(f, "{} at 0x{:X} is valid (0x{:0digits$X})", self.name, self.offset, self.stored, digits = digits),
+            false => write!(f, "{} at 0x{:X} is 0x{:0digits$X}, should be 0x{:0digits$X}", self.name, self.offset, self.stored, self.computed, digits = digits)
+        }
+    }
+}
+
+/// Compute the CRC16 used by NDS headers, which is the MODBUS variant.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &b| {
+        (0..8).fold(crc ^ b as u16, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xA001,
+            _ => crc >> 1
+        })
+    })
+}
+
+fn require_length(rom: &[u8], length: usize, kind: &str) -> Result<()> {
+    match rom.len() < length {
+        true => Err(Error::invalid(format!("The file is {} bytes, too short to hold a {} header", rom.len(), kind))),
+        false => Ok(())
+    }
+}
+
+fn gba_complement(rom: &[u8]) -> Checksum {
+    let computed = rom[0xA0..0xBD].iter().fold(0u8, |chk, &b| chk.wrapping_sub(b)).wrapping_sub(0x19);
+    
+    Checksum { name: "GBA header complement", offset: 0xBD, size: 1, stored: rom[0xBD] as u32, computed: computed as u32 }
+}
+
+fn gb_header_checksum(rom: &[u8]) -> Checksum {
+    let computed = rom[0x134..0x14D].iter().fold(0u8, |chk, &b| chk.wrapping_sub(b).wrapping_sub(1));
+    
+    Checksum { name: "GB header checksum", offset: 0x14D, size: 1, stored: rom[0x14D] as u32, computed: computed as u32 }
+}
+
+/// The global checksum sums every byte but its own, so it depends on the
+/// header checksum and must be computed after it is fixed.
+fn gb_global_checksum(rom: &[u8]) -> Checksum {
+    let computed = rom.iter().enumerate().filter(|&(i, _)| i != 0x14E && i != 0x14F).fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+    let stored = (rom[0x14E] as u32) << 8 | rom[0x14F] as u32;
+    
+    Checksum { name: "GB global checksum", offset: 0x14E, size: 2, stored, computed: computed as u32 }
+}
+
+fn nds_header_crc(rom: &[u8]) -> Checksum {
+    let stored = rom[0x15E] as u32 | (rom[0x15F] as u32) << 8;
+    
+    Checksum { name: "NDS header CRC16", offset: 0x15E, size: 2, stored, computed: crc16(&rom[..0x15E]) as u32 }
+}
+
+/// Check every header checksum a ROM of the given kind carries, without
+/// changing anything.
+///
+/// The GB global checksum is computed against the header checksum as stored,
+/// so a bad header checksum also shows up as a bad global checksum.
+pub fn check_checksums(kind: RomKind, rom: &[u8]) -> Result<Vec<Checksum>> {
+    match kind {
+        RomKind::Gba => {
+            require_length(rom, 0xC0, "GBA")?;
+            Ok(vec![gba_complement(rom)])
+        },
+        RomKind::Gb => {
+            require_length(rom, 0x150, "GB")?;
+            Ok(vec![gb_header_checksum(rom), gb_global_checksum(rom)])
+        },
+        RomKind::Nds => {
+            require_length(rom, 0x160, "NDS")?;
+            Ok(vec![nds_header_crc(rom)])
+        }
+    }
+}
+
+/// Recompute and store every header checksum a ROM of the given kind carries.
+///
+/// Returns each checksum as it was found, before fixing, so that callers can
+/// report which ones changed.
+pub fn fix_checksums(kind: RomKind, rom: &mut [u8]) -> Result<Vec<Checksum>> {
+    let mut out = Vec::new();
+    
+    for check in check_checksums(kind, rom)?.into_iter() {
+        //Earlier fixes may change what later checksums cover.
+        let check = match check.offset {
+            0x14E if kind == RomKind::Gb => gb_global_checksum(rom),
+            _ => check
+        };
+        
+        //The GB global checksum is the only big-endian one.
+        let bytes = match (check.size, kind) {
+            (1, _) => vec![check.computed as u8],
+            (_, RomKind::Gb) => vec![(check.computed >> 8) as u8, check.computed as u8],
+            _ => vec![check.computed as u8, (check.computed >> 8) as u8]
+        };
+        rom[check.offset..check.offset + bytes.len()].copy_from_slice(&bytes);
+        
+        out.push(check);
+    }
+    
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use awsmimg::checksum::*;
+    
+    #[test]
+    fn fix_header_checksums() {
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        
+        let original : Vec<u8> = (0..0x200).map(|i| (i * 13 % 256) as u8).collect();
+        
+        for &kind in [RomKind::Gba, RomKind::Gb, RomKind::Nds].iter() {
+            let mut rom = original.clone();
+            assert!(check_checksums(kind, &rom).unwrap().iter().any(|c| !c.is_valid()));
+            fix_checksums(kind, &mut rom).unwrap();
+            assert!(check_checksums(kind, &rom).unwrap().iter().all(|c| c.is_valid()));
+        }
+        
+        let mut rom = original.clone();
+        fix_checksums(RomKind::Gb, &mut rom).unwrap();
+        let checks = check_checksums(RomKind::Gb, &rom).unwrap();
+        assert_eq!(checks[1].to_string(), format!("GB global checksum at 0x14E is valid (0x{:04X})", checks[1].computed));
+        
+        //A damaged header checksum is fixed without changing the global
+        //checksum, which was computed with the correct one.
+        rom[0x14D] ^= 0xFF;
+        let checks = fix_checksums(RomKind::Gb, &mut rom).unwrap();
+        assert!(!checks[0].is_valid() && checks[1].is_valid());
+        assert!(check_checksums(RomKind::Gb, &rom).unwrap().iter().all(|c| c.is_valid()));
+        assert_eq!(checks[0].to_string(), format!("GB header checksum at 0x14D is 0x{:02X}, should be 0x{:02X}", rom[0x14D] ^ 0xFF, rom[0x14D]));
+        
+        assert!(check_checksums(RomKind::Nds, &rom[..0x100]).is_err());
+        assert_eq!(interpret_rom_kind_name("GBA"), Some(RomKind::Gba));
+    }
+}
@@ -3,6 +3,8 @@ use std::io::{Read, Write};
 use std::cmp::{Ord, Ordering, min};
 use std::collections::binary_heap::BinaryHeap;
 
+use awsmimg::error::Error;
+
 #[derive(Copy, Clone)]
 enum AGBHuffmanNode {
     Branch(usize), //Index of next tree node to read a bit from.
@@ -19,8 +21,10 @@ type AGBHuffmanTree = (AGBHuffmanNode, AGBHuffmanNode);
 /// https://problemkaputt.de/gbatek.htm#biosdecompressionfunctions
 /// 
 /// Data header must be present and valid; failing to provide such a header will
-/// cause read operations to fail with an InvalidData error. Errors raised by
-/// the underlying Read object will pass through this object.
+/// cause read operations to fail with an InvalidData error carrying an
+/// awsmimg::Error::CorruptData, which records how far into the compressed
+/// stream the problem was found. Errors raised by the underlying Read object
+/// will pass through this object.
 /// 
 /// AGB compressed data formats contain internal size information that
 /// constitutes a limit on how many bytes can be decompressed from the reader.
@@ -42,6 +46,7 @@ struct AGBHuffmanDecompressor<'a, R: Read + 'a> {
     decompressed_cnt: usize, //Number of bytes decompressed so far.
     bitbuffer: u32, //Remaining already-read bits
     bitbuffer_len: u8, //Number of valid bits remaining in the buffer
+    offset: u64, //Number of compressed bytes read so far.
 }
 
 impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
@@ -55,16 +60,23 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
             initialized: false,
             decompressed_cnt: 0,
             bitbuffer: 0,
-            bitbuffer_len: 0
+            bitbuffer_len: 0,
+            offset: 0
         }
     }
     
+    /// Report the compressed stream as corrupt at the current read position.
+    fn corrupt(&self, reason: &str) -> io::Error {
+        Error::CorruptData { offset: self.offset, reason: reason.to_string() }.into()
+    }
+    
     fn read_huffman_header(&mut self) -> io::Result<()> {
         let mut hdr = [0u8; 4];
         let readbytes = self.r.read(&mut hdr)?;
+        self.offset += readbytes as u64;
         
         if readbytes < hdr.len() {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The AGB Huffman general header extends past the end of the file."));
+            return Err(self.corrupt("The AGB Huffman general header extends past the end of the file."));
         }
         
         //AGB is little endian so I THINK this works!?
@@ -73,7 +85,7 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
         self.internal_size = ((hdr[3] as u32) << 16) | ((hdr[2] as u32) << 8) | (hdr[1] as u32);
         
         if self.header_type != 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "This is not AGB Huffman data."))
+            return Err(Error::CorruptData { offset: 0, reason: "This is not AGB Huffman data.".to_string() }.into())
         }
         
         Ok(())
@@ -82,9 +94,10 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
     fn read_huffman_tree(&mut self) -> io::Result<()> {
         let mut hdr = [0u8; 1];
         let readbytes = self.r.read(&mut hdr)?;
+        self.offset += readbytes as u64;
         
         if readbytes < hdr.len() {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The AGB Huffman tree header extends past the end of the file."));
+            return Err(self.corrupt("The AGB Huffman tree header extends past the end of the file."));
         }
         
         let treesize = ((hdr[0] + 1) * 2).into();
@@ -92,9 +105,10 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
         rawtree.resize(treesize, 0);
         
         let readbytes2 = self.r.read(&mut rawtree.get_mut(0..treesize).unwrap())?;
+        self.offset += readbytes2 as u64;
         
-        if readbytes < rawtree.len() {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The AGB Huffman tree data extends past the end of the file."));
+        if readbytes2 < rawtree.len() {
+            return Err(self.corrupt("The AGB Huffman tree data extends past the end of the file."));
         }
         
         self.tree.resize(treesize / 2, (AGBHuffmanNode::Leaf(0), AGBHuffmanNode::Leaf(0)));
@@ -154,6 +168,7 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
         }
         
         bytes_read = self.r.read(&mut buf[0..bytes_needed])?;
+        self.offset += bytes_read as u64;
         
         if (bytes_read == 0) {
             return Ok(())
@@ -178,7 +193,7 @@ impl <'a, R: Read + 'a> AGBHuffmanDecompressor<'a, R> {
 
         //Raise error if we really can't get more bits
         if (self.bitbuffer_len < 1) {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The AGB Huffman datastream ended before we could finish decompressing."));
+            return Err(self.corrupt("The AGB Huffman datastream ended before we could finish decompressing."));
         }
         
         let nextbit = self.bitbuffer & 0x01;
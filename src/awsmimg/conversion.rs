@@ -1,6 +1,26 @@
-use image::{GenericImage, Pixel, Primitive, ImageBuffer, LumaA};
+use image::{GenericImage, Pixel, Primitive, ImageBuffer, LumaA, Rgba};
 use num::NumCast;
+use awsmimg::error::{Error, Result};
 use std::ops::Div;
+use std::cmp::min;
+
+/// Given the position of a pixel within an image, determine where that pixel
+/// belongs within a stream of tile-ordered index data.
+/// 
+/// Tiles are ordered left-to-right, top-to-bottom within the image, and the
+/// pixels of each tile are stored row-major before the next tile begins.
+pub fn tiled_index_position(x: u32, y: u32, width: u32, tsize: (u32, u32)) -> usize {
+    let (tw, th) = tsize;
+    
+    let tx = x / tw;
+    let px = x % tw;
+    let ty = y / th;
+    let py = y % th;
+    
+    let itile = ty * (width / tw) + tx;
+    
+    (itile * tw * th + py * tw + px) as usize
+}
 
 /// Given an image, produce a stream of index data to encode by interpreting
 /// the grayscale values of the image as indexes.
@@ -26,37 +46,227 @@ pub fn indexes_from_luma<I, P, S>(image: &I, maxcol: S, tsize: (u32, u32)) -> Ve
     where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
     
     let (width, height) = image.dimensions();
-    let (tw, th) = tsize;
     let mut out : Vec<S> = Vec::with_capacity(width as usize * height as usize);
     let imgmax = S::max_value();
     let imgmax: f32 = NumCast::from(imgmax).unwrap();
     let maxcol_adj: f32 = NumCast::from(maxcol).unwrap();
     
-    let tlen = tw * th;
-    
     for (ix, iy, pixel) in image.pixels() {
         let la = pixel.to_luma_alpha();
         let gray = la[0].to_f32().unwrap();
         let alpha = la[1].to_u8().unwrap();
         
-        let tx = ix / tw;
-        let px = ix % tw;
-        let ty = iy / tw;
-        let py = iy % tw;
+        let outidx = tiled_index_position(ix, iy, width, tsize);
         
-        let itile = ty * (width / tw) + tx;
-        let outidx = (itile * tlen + py * tw + px) as usize;
+        if outidx >= out.len() {
+            if alpha == 0u8 {
+                continue;
+            }
         
-        if outidx >= out.len() && alpha != 0u8 {
             out.resize(outidx + 1, S::from(0u8).unwrap());
         }
         
-        out[outidx] = S::from((gray / imgmax * maxcol_adj).floor()).unwrap();
+        out[outidx] = S::from(luma_index(gray, imgmax, maxcol_adj)).unwrap();
+    }
+
+    out
+}
+
+/// Scale a gray value, out of imgmax, to the index indexes_from_luma gives it
+/// out of maxcol.
+pub fn luma_index(gray: f32, imgmax: f32, maxcol: f32) -> f32 {
+    (gray / imgmax * maxcol).floor()
+}
+
+/// Given an image and a palette, produce a stream of index data to encode by
+/// finding each pixel's color within the palette.
+/// 
+/// Tiles are ordered the same way as indexes_from_luma, and fully transparent
+/// pixels are treated the same way: they map to index 0 and do not extend the
+/// length of the converted data.
+/// 
+/// Colors are compared by their RGB components only. If a pixel's color does
+/// not appear in the palette, this function yields an IndexOutOfRange error
+/// that reports the coordinates of the first offending pixel. If nearest is true, such
+/// pixels will instead be mapped to the palette entry with the smallest
+/// squared distance in RGB space. Ties are broken in favor of the lowest
+/// index.
+/// 
+/// Only entries up to maxcol can be encoded, so the nearest color is chosen
+/// from those alone, and a pixel whose color first appears past maxcol is an
+/// error even if nearest is true.
+pub fn indexes_from_palette<I, P, S>(image: &I, palette: &[Rgba<u8>], maxcol: u16, tsize: (u32, u32), nearest: bool) -> Result<Vec<u8>>
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    
+    let (width, height) = image.dimensions();
+    let mut out : Vec<u8> = Vec::with_capacity(width as usize * height as usize);
+    let mut unmatched = None;
+    let mut count = 0;
+    
+    if palette.is_empty() {
+        return Err(Error::invalid("Cannot map an image onto an empty palette"));
     }
+    
+    let usable = &palette[..min(palette.len(), maxcol as usize + 1)];
+    
+    for (ix, iy, pixel) in image.pixels() {
+        let rgba = rgba8_from_pixel(pixel);
+        let outidx = tiled_index_position(ix, iy, width, tsize);
+        
+        if outidx >= out.len() {
+            if rgba[3] == 0 {
+                continue;
+            }
+            
+            out.resize(outidx + 1, 0);
+        }
+        
+        if rgba[3] == 0 {
+            out[outidx] = 0;
+            continue;
+        }
+        
+        let exact = palette.iter().position(|c| c[0] == rgba[0] && c[1] == rgba[1] && c[2] == rgba[2]);
+        
+        out[outidx] = match (exact, nearest) {
+            (Some(i), _) if i > maxcol as usize => {
+                return Err(Error::invalid(format!("Pixel ({}, {}) is color #{:02X}{:02X}{:02X}, palette entry {}, but this format can only use entries 0 to {}", ix, iy, rgba[0], rgba[1], rgba[2], i, maxcol)));
+            },
+            (Some(i), _) => i as u8,
+            (None, true) => nearest_palette_index(&rgba, usable) as u8,
+            (None, false) => {
+                unmatched = unmatched.or(Some((ix, iy, rgba)));
+                count += 1;
+                0
+            }
+        };
+    }
+    
+    if let Some((x, y, c)) = unmatched {
+        return Err(Error::IndexOutOfRange { x, y, color: [c[0], c[1], c[2]], count });
+    }
+    
+    Ok(out)
+}
+
+/// Given row-major index data, where transparent pixels are None, produce a
+/// stream of tile-ordered index data.
+/// 
+/// Transparent pixels are treated the same way as by indexes_from_luma: they
+/// become index 0 and do not extend the length of the converted data.
+pub fn tiled_indexes_from_rows(rows: &[Option<u8>], width: u32, tsize: (u32, u32)) -> Vec<u8> {
+    let mut out : Vec<u8> = Vec::with_capacity(rows.len());
+    
+    for (i, idx) in rows.iter().enumerate() {
+        let outidx = tiled_index_position(i as u32 % width, i as u32 / width, width, tsize);
+        
+        match *idx {
+            Some(idx) => {
+                if outidx >= out.len() {
+                    out.resize(outidx + 1, 0);
+                }
+                out[outidx] = idx;
+            },
+            None => {
+                if outidx < out.len() {
+                    out[outidx] = 0;
+                }
+            }
+        }
+    }
+    
+    out
+}
+
+/// Determine which tiles of an image hold at least one pixel that is not
+/// fully transparent.
+/// 
+/// Tiles are listed in the same order as the tile-ordered index data produced
+/// from the image, so the result says which parts of that data carry any
+/// visible content.
+pub fn visible_tiles<I, P, S>(image: &I, tsize: (u32, u32)) -> Vec<bool>
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    
+    let (width, height) = image.dimensions();
+    let tstride = (tsize.0 * tsize.1) as usize;
+    let mut out = vec![false; (width / tsize.0 * height / tsize.1) as usize];
+    
+    for (ix, iy, pixel) in image.pixels() {
+        let tile = tiled_index_position(ix, iy, width, tsize) / tstride;
+        
+        if tile < out.len() && rgba8_from_pixel(pixel)[3] != 0 {
+            out[tile] = true;
+        }
+    }
+    
+    out
+}
 
+/// Convert any pixel into an 8-bit RGBA color.
+pub fn rgba8_from_pixel<P, S>(pixel: P) -> Rgba<u8> where P: Pixel<Subpixel=S>, S: Primitive {
+    let rgba = pixel.to_rgba();
+    let imgmax : f32 = NumCast::from(S::max_value()).unwrap();
+    let mut out = Rgba([0u8; 4]);
+    
+    for i in 0..4 {
+        let channel : f32 = NumCast::from(rgba[i]).unwrap();
+        out[i] = (channel / imgmax * 255f32).round() as u8;
+    }
+    
     out
 }
 
+/// Find the palette entry closest to a given color in RGB space.
+pub fn nearest_palette_index(color: &Rgba<u8>, palette: &[Rgba<u8>]) -> usize {
+    let mut best = 0;
+    let mut best_dist = u32::MAX;
+    
+    for (i, c) in palette.iter().enumerate() {
+        let dr = color[0] as i32 - c[0] as i32;
+        let dg = color[1] as i32 - c[1] as i32;
+        let db = color[2] as i32 - c[2] as i32;
+        let dist = (dr * dr + dg * dg + db * db) as u32;
+        
+        if dist < best_dist {
+            best = i;
+            best_dist = dist;
+        }
+    }
+    
+    best
+}
+
+/// Determine the size of an image holding a stream of decoded index data.
+/// 
+/// If isize is given, it is used as-is; otherwise an approximately square
+/// image large enough to hold every tile is chosen. Yields an error if the
+/// data or the image size are not a multiple of the tile size.
+pub fn indexed_image_size(len: usize, tsize: (u32, u32), isize: Option<(u32, u32)>) -> Result<(u32, u32)> {
+    let (tw, th) = tsize;
+    let tstride = tw * th;
+    let tcount = len as u32 / tstride;
+    
+    //Data length must be cleanly divided by the length of a single tile.
+    if tcount * tstride != len as u32 {
+        return Err(Error::invalid(format!("{} pixels of data do not make up a whole number of {}x{} tiles", len, tw, th)));
+    }
+    
+    let (iw, ih) = match isize {
+        Some((w, h)) => (w, h),
+        None => {
+            let iw = (tcount as f32).sqrt().ceil() as u32 * tw;
+            (iw, ((tcount as f32) / (iw / tw) as f32).ceil() as u32 * th)
+        }
+    };
+    
+    //Image size must cleanly divide by tile size.
+    if (iw % tw != 0) || (ih % th != 0) {
+        return Err(Error::BadDimensions { width: iw, height: ih, multiple: tsize });
+    }
+    
+    Ok((iw, ih))
+}
+
 /// Given a stream of decoded index data, produce an image representing the
 /// data with color indicies represented as grayscale values and each tile
 /// placed left-to-right in the image.
@@ -74,39 +284,16 @@ pub fn indexes_from_luma<I, P, S>(image: &I, maxcol: S, tsize: (u32, u32)) -> Ve
 /// image not holding decoded index data will instead be fully transparent
 /// pixels. As a result, the pixel format of returned images will be locked to
 /// LumaA pixels.
-pub fn luma_from_indexes<'a, S>(data: Vec<S>, maxcol: u16, tsize: (u32, u32), isize: Option<(u32, u32)>) -> Option<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where S: Primitive + 'a {
-    let mut iw;
-    let mut ih;
+pub fn luma_from_indexes<'a, S>(data: Vec<S>, maxcol: u16, tsize: (u32, u32), isize: Option<(u32, u32)>) -> Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where S: Primitive + 'a {
+    let (iw, ih) = indexed_image_size(data.len(), tsize, isize)?;
     let (tw, th) = tsize;
     let tstride = tw * th;
-    let tcount = data.len() as u32 / tstride;
-    
-    //Data length must be cleanly divided by the length of a single tile.
-    if tcount * tstride != data.len() as u32 {
-        return None;
-    }
-    
-    match isize {
-        Some((w, h)) => {
-            iw = w;
-            ih = h;
-        },
-        None => {
-            iw = (tcount as f32).sqrt().ceil() as u32 * tw;
-            ih = ((tcount as f32) / (iw / tw) as f32).ceil() as u32 * th;
-        }
-    };
-    
-    //Image size must cleanly divide by tile size.
-    if (iw % tw != 0) || (ih % th != 0) {
-        return None;
-    }
     
     let maxcol : f32 = NumCast::from(maxcol).unwrap();
     let colscale : f32 = 255f32 / maxcol;
     
     //TODO: What if we have a format that needs more than 8 bits of precision?
-    Some(Box::new(ImageBuffer::from_fn(iw, ih, |x, y| {
+    Ok(Box::new(ImageBuffer::from_fn(iw, ih, |x, y| {
         let tx = x / tw; // tile units
         let ty = y / th;
         
@@ -126,13 +313,37 @@ pub fn luma_from_indexes<'a, S>(data: Vec<S>, maxcol: u16, tsize: (u32, u32), is
     })))
 }
 
+/// Given a stream of decoded index data and a palette, produce an image
+/// showing the data in its actual colors, with each tile placed left-to-right
+/// in the image.
+/// 
+/// Image sizing follows the same rules as luma_from_indexes. Index 0 is
+/// treated as transparent, as are parts of the image not holding decoded
+/// index data and indexes beyond the end of the palette.
+pub fn rgba_from_indexes<'a, S>(data: Vec<S>, palette: &[Rgba<u8>], tsize: (u32, u32), isize: Option<(u32, u32)>) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where S: Primitive + 'a {
+    let (iw, ih) = indexed_image_size(data.len(), tsize, isize)?;
+    
+    Ok(Box::new(ImageBuffer::from_fn(iw, ih, |x, y| {
+        let tileidx = tiled_index_position(x, y, iw, tsize);
+        let index : usize = match data.get(tileidx) {
+            Some(i) => NumCast::from(*i).unwrap(),
+            None => 0
+        };
+        
+        match (index, palette.get(index)) {
+            (0, _) | (_, None) => Rgba([0u8, 0, 0, 0]),
+            (_, Some(c)) => Rgba([c[0], c[1], c[2], 255])
+        }
+    })))
+}
+
 #[cfg(test)]
 mod test {
     extern crate image;
     extern crate num;
     
-    use awsmimg::conversion::{indexes_from_luma, luma_from_indexes};
-    use image::{GenericImage, Pixel, ImageBuffer, LumaA};
+    use awsmimg::conversion::{indexes_from_luma, luma_from_indexes, indexes_from_palette};
+    use image::{GenericImage, Pixel, ImageBuffer, LumaA, Rgba};
     use num::NumCast;
     
     #[test]
@@ -162,10 +373,46 @@ mod test {
             grays0.push(NumCast::from(pixel.to_rgba()[0]).unwrap());
         }
         
-        for (_, _, pixel) in test_output.pixels() {
+        for pixel in test_output.pixels() {
             grays1.push(NumCast::from(pixel.to_rgba()[0]).unwrap());
         }
         
         assert_eq!(&grays0, &grays1);
     }
+    
+    #[test]
+    fn palette_map_test() {
+        let palette = vec![Rgba([0u8, 0, 0, 255]), Rgba([255u8, 0, 0, 255]), Rgba([0u8, 0, 255, 255])];
+        let test_input : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(16, 8, |x, _| {
+            palette[(x / 8) as usize + 1]
+        });
+        
+        let test_out = indexes_from_palette(&test_input, &palette, 15, (8, 8), false).unwrap();
+        
+        assert_eq!(test_out.len(), 128);
+        assert!(test_out[0..64].iter().all(|&i| i == 1));
+        assert!(test_out[64..128].iter().all(|&i| i == 2));
+    }
+    
+    #[test]
+    fn palette_map_unmatched_test() {
+        let palette = vec![Rgba([0u8, 0, 0, 255]), Rgba([255u8, 0, 0, 255])];
+        let test_input : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(8, 8, |x, y| {
+            if x == 3 && y == 5 { Rgba([250u8, 10, 0, 255]) } else { Rgba([0u8, 0, 0, 255]) }
+        });
+        
+        let err = indexes_from_palette(&test_input, &palette, 15, (8, 8), false).unwrap_err();
+        assert!(err.to_string().contains("(3, 5)"));
+        assert!(err.to_string().contains("(1 unmatched pixels in total)"));
+        
+        let test_out = indexes_from_palette(&test_input, &palette, 15, (8, 8), true).unwrap();
+        assert_eq!(test_out[5 * 8 + 3], 1);
+        
+        //Entry 1 is past a maxcol of 0: nearest matches avoid it, and exact
+        //matches are an error.
+        let test_out = indexes_from_palette(&test_input, &palette, 0, (8, 8), true).unwrap();
+        assert_eq!(test_out[5 * 8 + 3], 0);
+        let err = indexes_from_palette(&test_input, &[palette[1], palette[0]], 0, (8, 8), true).unwrap_err();
+        assert!(err.to_string().contains("palette entry 1"));
+    }
 }
@@ -2,35 +2,111 @@ use std::ops::Div;
 
 use std::io;
 use std::io::Read;
-use image::{GenericImage, ImageBuffer, Primitive, Pixel, LumaA};
+use image::{ImageBuffer, Primitive, LumaA, Rgba};
 
-use awsmimg::formats::{IndexedGraphicsProperties, IndexedFormat};
-use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder};
-use awsmimg::conversion::luma_from_indexes;
+use awsmimg::error::Result;
+use awsmimg::formats::{IndexedGraphicsProperties, TilemapProperties, DirectFormat};
+use awsmimg::formats::agb::AGB16Encoder;
+use awsmimg::conversion::{luma_from_indexes, rgba_from_indexes};
+use awsmimg::tiles::{MapEntry, split_tiles, indexes_from_map};
+use awsmimg::render::render_scene;
+use awsmimg::sprites::{ObjMapping, SpriteLayout, sheet_indexes_from_sprites};
 
 pub trait IndexedGraphicsDecoder : IndexedGraphicsProperties {
     /// Decode previously-encoded data into a vector of index data.
     /// 
     /// The given size parameter may be used to limit the amount of image data
-    /// decoded. This constitutes an upper bound on how many bytes are allowed
-    /// to be read from the decoder's data source. If the decoder's data source
-    /// has fewer bytes than allowed by the size parameter, the decoder should
-    /// treat the data source's remaining number of bytes as the limiting
-    /// factor.
+    /// decoded. This constitutes an upper bound on how many pixels (indexes)
+    /// are to be decoded, regardless of how many bits each pixel occupies in
+    /// the format. If the decoder's data source runs out before that many
+    /// pixels have been decoded, the decoder should treat the data source's
+    /// remaining data as the limiting factor.
     /// 
     /// If the format being decoded contains size information or a stop symbol,
     /// that information shall constitute a further upper bound on decoding.
     /// Lengths so internally specified should be respected the same as the
     /// size parameter or data underrun conditions.
     /// 
-    /// In the event that any aformentioned limitation on decoding causes the
-    /// underlying datastream to terminate improperly, the decoder must yield
-    /// an error instead of attempting to reconstruct potentially corrupted
-    /// data. The meaning of "improper termination" is implementation defined.
-    /// Implementations of decoders must take care to ensure that any situation
-    /// where data is being misinterpreted, misdecoded, or is incomplete
-    /// results in an error rather than invalid data.
+    /// Unlike decode_palette, running out of data is not an error by itself:
+    /// formats without internal structure, such as plain tile patterns, yield
+    /// every pixel the data holds, so short input gives short output. Formats
+    /// with internal structure, such as compressed streams, must yield an
+    /// error if any aforementioned limitation on decoding causes the
+    /// underlying datastream to terminate improperly, instead of attempting
+    /// to reconstruct potentially corrupted data. The meaning of "improper
+    /// termination" is implementation defined.
     fn decode_indexes<P: Primitive>(&mut self, size: usize) -> io::Result<Vec<P>>;
+    
+    /// Decode previously-encoded palette data into a vector of RGBA colors.
+    /// 
+    /// Exactly count colors must be decoded. If the data source runs out
+    /// before that many colors have been read, the decoder must yield an error
+    /// rather than a shortened palette.
+    fn decode_palette(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>>;
+}
+
+/// Object-safe counterpart to IndexedGraphicsDecoder.
+///
+/// This trait fixes the decoded index type to u8 so that decoders can be
+/// boxed and selected at runtime. It is implemented for every
+/// IndexedGraphicsDecoder, and boxed decoders in turn implement
+/// IndexedGraphicsDecoder, so they may be passed to any of the decode_*
+/// functions.
+pub trait DynIndexedGraphicsDecoder : IndexedGraphicsProperties {
+    /// Decode up to size indexes. See IndexedGraphicsDecoder::decode_indexes.
+    fn decode_index_data(&mut self, size: usize) -> io::Result<Vec<u8>>;
+    
+    /// Decode count colors. See IndexedGraphicsDecoder::decode_palette.
+    fn decode_palette_colors(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>>;
+}
+
+impl<T> DynIndexedGraphicsDecoder for T where T: IndexedGraphicsDecoder {
+    fn decode_index_data(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        self.decode_indexes(size)
+    }
+    
+    fn decode_palette_colors(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>> {
+        self.decode_palette(count)
+    }
+}
+
+impl<'a> IndexedGraphicsProperties for Box<dyn DynIndexedGraphicsDecoder + 'a> {
+    fn tile_size(&self) -> (u32, u32) {
+        (**self).tile_size()
+    }
+    
+    fn attribute_size(&self) -> (u32, u32) {
+        (**self).attribute_size()
+    }
+    
+    fn palette_maxcol(&self) -> u16 {
+        (**self).palette_maxcol()
+    }
+    
+    fn bits_per_pixel(&self) -> u32 {
+        (**self).bits_per_pixel()
+    }
+}
+
+impl<'a> IndexedGraphicsDecoder for Box<dyn DynIndexedGraphicsDecoder + 'a> {
+    fn decode_indexes<P: Primitive>(&mut self, size: usize) -> io::Result<Vec<P>> {
+        let data = (**self).decode_index_data(size)?;
+        Ok(data.into_iter().map(|index| P::from(index).unwrap()).collect())
+    }
+    
+    fn decode_palette(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>> {
+        (**self).decode_palette_colors(count)
+    }
+}
+
+/// Represents a struct which can decode data in a particular direct color
+/// image format back into color images.
+pub trait DirectGraphicsDecoder {
+    /// Decode previously-encoded data into an image of the given size.
+    /// 
+    /// If the data source runs out before the whole image has been decoded,
+    /// the decoder must yield an error rather than a partial image.
+    fn decode_colors(&mut self, width: u32, height: u32) -> io::Result<ImageBuffer<Rgba<u8>, Vec<u8>>>;
 }
 
 /// Given an image and a decoder, decode index data by interpreting the
@@ -39,26 +115,104 @@ pub trait IndexedGraphicsDecoder : IndexedGraphicsProperties {
 /// The grayscale-image-as-index-data approach is useful because it assigns an
 /// unambiguous color to every index, allowing editing of the graphical data
 /// using image manipulation tools that don't provide palette editing.
-pub fn decode_indexes_as_image<'a, E>(enc: &mut E, size: usize, isize: Option<(u32, u32)>) -> io::Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+pub fn decode_indexes_as_image<'a, E>(enc: &mut E, size: usize, isize: Option<(u32, u32)>) -> Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
     let indexes : Vec<u8> = enc.decode_indexes(size)?;
-    let img = luma_from_indexes(indexes, enc.palette_maxcol(), enc.tile_size(), isize);
-    match img {
-        Some(i) => Ok(i),
-        None => Err(io::Error::new(io::ErrorKind::InvalidInput, ""))
-    }
+    luma_from_indexes(indexes, enc.palette_maxcol(), enc.tile_size(), isize)
+}
+
+/// Given a decoder and a palette, decode index data into an image showing the
+/// data in its actual colors.
+/// 
+/// Index 0 is treated as transparent, as are indexes beyond the end of the
+/// palette.
+pub fn decode_indexes_as_color_image<'a, E>(enc: &mut E, size: usize, isize: Option<(u32, u32)>, palette: &[Rgba<u8>]) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+    let indexes : Vec<u8> = enc.decode_indexes(size)?;
+    rgba_from_indexes(indexes, palette, enc.tile_size(), isize)
+}
+
+
+pub trait MapDecoder : TilemapProperties {
+    /// Decode a previously-encoded tilemap of the given size, in tiles.
+    /// 
+    /// Entries are returned in left-to-right, top-to-bottom order. If the
+    /// data source ends before the whole map has been read, or the map size
+    /// cannot be represented in this map format, the decoder must yield an
+    /// error.
+    fn decode_map(&mut self, width: u32, height: u32) -> io::Result<Vec<MapEntry>>;
+}
+
+/// Given a decoder for a set of tiles and a tilemap, rebuild the full image
+/// the map describes with color indicies represented as grayscale values.
+/// 
+/// map_size is the size of the map in tiles. Flips are honored; palette banks
+/// are not, as grayscale images only represent indexes within a bank.
+pub fn decode_tilemap_as_image<'a, E>(dec: &mut E, size: usize, map: &[MapEntry], map_size: (u32, u32)) -> Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+    let tsize = dec.tile_size();
+    let indexes : Vec<u8> = dec.decode_indexes(size)?;
+    let screen = indexes_from_map(&split_tiles(&indexes, tsize), map, tsize);
+    luma_from_indexes(screen, dec.palette_maxcol(), tsize, Some((map_size.0 * tsize.0, map_size.1 * tsize.1)))
+}
+
+/// Given a decoder for a set of tiles, a decoder for a tilemap, and a palette,
+/// render the scene the hardware would display.
+/// 
+/// map_size is the size of the map in tiles. Flips and palette banks are both
+/// honored; formats that can index the whole palette from a single tile ignore
+/// palette banks, as they do on hardware. This is the inverse of
+/// encode_image_as_tilemap.
+pub fn decode_scene_as_image<'a, E, M>(dec: &mut E, size: usize, mapdec: &mut M, map_size: (u32, u32), palette: &[Rgba<u8>]) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a, M: MapDecoder + 'a {
+    let map = mapdec.decode_map(map_size.0, map_size.1)?;
+    decode_mapped_scene_as_image(dec, size, &map, map_size, palette)
+}
+
+/// Given a decoder for a set of tiles, an already-decoded tilemap, and a
+/// palette, render the scene the hardware would display.
+/// 
+/// This is decode_scene_as_image for maps that have already been decoded.
+pub fn decode_mapped_scene_as_image<'a, E>(dec: &mut E, size: usize, map: &[MapEntry], map_size: (u32, u32), palette: &[Rgba<u8>]) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+    let tsize = dec.tile_size();
+    let indexes : Vec<u8> = dec.decode_indexes(size)?;
+    let bank_size = match dec.palette_maxcol() {
+        maxcol if maxcol < 255 => maxcol as usize + 1,
+        _ => 0
+    };
+    
+    Ok(Box::new(render_scene(&split_tiles(&indexes, tsize), map, map_size, tsize, bank_size, palette)))
+}
+
+/// Given a decoder for sprite memory, rebuild a sprite sheet from the frames
+/// it holds, with color indicies represented as grayscale values.
+/// 
+/// This is the inverse of encode_image_as_sprites. Frames are arranged in an
+/// approximately square sheet.
+pub fn decode_sprites_as_image<'a, E>(dec: &mut E, size: usize, obj_size: (u32, u32), mapping: ObjMapping) -> Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+    let layout = SpriteLayout::new(obj_size, mapping, dec)?;
+    let indexes : Vec<u8> = dec.decode_indexes(size)?;
+    let (sheet, sheet_size) = sheet_indexes_from_sprites(&indexes, &layout);
+    
+    luma_from_indexes(sheet, dec.palette_maxcol(), layout.tsize, Some(sheet_size))
+}
+
+/// Given a decoder for sprite memory and a palette, rebuild a sprite sheet
+/// from the frames it holds, showing the data in its actual colors.
+pub fn decode_sprites_as_color_image<'a, E>(dec: &mut E, size: usize, obj_size: (u32, u32), mapping: ObjMapping, palette: &[Rgba<u8>]) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> where E: IndexedGraphicsDecoder + 'a {
+    let layout = SpriteLayout::new(obj_size, mapping, dec)?;
+    let indexes : Vec<u8> = dec.decode_indexes(size)?;
+    let (sheet, sheet_size) = sheet_indexes_from_sprites(&indexes, &layout);
+    
+    rgba_from_indexes(sheet, palette, layout.tsize, Some(sheet_size))
 }
 
-/// Given an image, a writer, and a format description, encode index data by
-/// interpreting the grayscale values of an image as indicies.
+/// Given a reader and a direct color format description, decode an image of
+/// the given size.
 ///
 /// This function allows access to built-in, private type implementations of
 /// these traits. It is currently not possible to access these types through any
-/// other means as they are private and IndexedGraphicsEncoder cannot be
+/// other means as they are private and DirectGraphicsDecoder cannot be
 /// dynamically dispatched.
-pub fn decode_indexes_as_image_with_format<'a, R>(format: IndexedFormat, r: &mut R, size: usize, imgsize: Option<(u32, u32)>) -> io::Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> where R: Read + 'a {
+pub fn decode_direct_color_as_image_with_format<'a, R>(format: DirectFormat, r: &mut R, width: u32, height: u32) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> where R: Read + 'a {
     match format {
-        IndexedFormat::AGB4 => decode_indexes_as_image(&mut AGB4Encoder::new(r), size, imgsize),
-        IndexedFormat::AGB8Tiled => decode_indexes_as_image(&mut AGB8Encoder::new_tiled(r), size, imgsize),
-        IndexedFormat::AGB8Chunky => decode_indexes_as_image(&mut AGB8Encoder::new_chunky(r), size, imgsize)
+        DirectFormat::AGB16 => Ok(AGB16Encoder::new_agb(r).decode_colors(width, height)?),
+        DirectFormat::NTR16 => Ok(AGB16Encoder::new_ntr(r).decode_colors(width, height)?)
     }
 }
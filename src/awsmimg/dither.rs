@@ -0,0 +1,226 @@
+use image::{GenericImage, ImageBuffer, Pixel, Primitive, Rgba};
+
+use awsmimg::conversion::rgba8_from_pixel;
+use awsmimg::quantize::reduce_to_bgr555;
+
+/// Methods of distributing quantization error when reducing color depth.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Dither {
+    None,           //Round every pixel to the nearest color
+    Bayer,          //Ordered dithering with an 8x8 Bayer threshold matrix
+    FloydSteinberg, //Error diffusion to the right and lower neighbors
+    TileStable      //Ordered dithering with a 2x2 matrix
+}
+
+pub fn interpret_dither_name(name_given: &str) -> Option<Dither> {
+    let name = name_given.to_ascii_lowercase();
+    
+    match name.as_ref() {
+        "none" => Some(Dither::None),
+        "bayer" => Some(Dither::Bayer),
+        "floyd-steinberg" | "fs" => Some(Dither::FloydSteinberg),
+        "tile" => Some(Dither::TileStable),
+        _ => None
+    }
+}
+
+const BAYER8: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21]
+];
+
+const BAYER2: [[u8; 2]; 2] = [
+    [0, 2],
+    [3, 1]
+];
+
+/// Determine the ordered dithering offset for a pixel, in the range
+/// (-0.5, 0.5).
+/// 
+/// The tile-stable pattern repeats every two pixels. Since every tile size in
+/// use is a multiple of two, a flat area of color dithers to identical tiles
+/// wherever it lies in the image, and each 4bpp byte holds the same pixel pair
+/// across a row. Both properties keep tile deduplication and compression
+/// effective.
+fn ordered_threshold(mode: Dither, x: u32, y: u32) -> f32 {
+    match mode {
+        Dither::Bayer => (BAYER8[(y % 8) as usize][(x % 8) as usize] as f32 + 0.5) / 64.0 - 0.5,
+        Dither::TileStable => (BAYER2[(y % 2) as usize][(x % 2) as usize] as f32 + 0.5) / 4.0 - 0.5,
+        _ => 0.0
+    }
+}
+
+fn clamp_channel(v: f32) -> u8 {
+    v.round().clamp(0.0, 255.0) as u8
+}
+
+/// Dither an image, pixel by pixel, onto some limited set of colors.
+/// 
+/// The choose function is handed the color each opaque pixel should ideally
+/// have once accumulated error or threshold offsets are applied, and must
+/// return its chosen result along with the color that result represents. The
+/// difference between the two is what gets diffused to neighboring pixels.
+/// spread is the size of a typical step between representable colors, and
+/// scales the ordered dithering thresholds.
+/// 
+/// Results are returned row-major. Fully transparent pixels yield None and do
+/// not participate in error diffusion.
+pub fn dither_pixels<I, P, S, T, F>(image: &I, mode: Dither, spread: f32, mut choose: F) -> Vec<Option<T>>
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, F: FnMut(Rgba<u8>) -> (T, Rgba<u8>) {
+    
+    let (width, height) = image.dimensions();
+    let mut out = Vec::with_capacity(width as usize * height as usize);
+    let mut error = vec![[0f32; 3]; width as usize * 2];
+    
+    for y in 0..height {
+        //Shift the next row's accumulated error into place.
+        let (cur, next) = error.split_at_mut(width as usize);
+        cur.copy_from_slice(next);
+        for e in next.iter_mut() {
+            *e = [0f32; 3];
+        }
+        
+        for x in 0..width {
+            let rgba = rgba8_from_pixel(image.get_pixel(x, y));
+            
+            if rgba[3] == 0 {
+                out.push(None);
+                continue;
+            }
+            
+            let offset = ordered_threshold(mode, x, y) * spread;
+            let diffused = error[x as usize];
+            let mut target = Rgba([0u8, 0, 0, rgba[3]]);
+            
+            for ch in 0..3 {
+                target[ch] = clamp_channel(rgba[ch] as f32 + offset + diffused[ch]);
+            }
+            
+            let (result, actual) = choose(target);
+            
+            if mode == Dither::FloydSteinberg {
+                for ch in 0..3 {
+                    let err = rgba[ch] as f32 + diffused[ch] - actual[ch] as f32;
+                    let xi = x as usize;
+                    let w = width as usize;
+                    
+                    if xi + 1 < w {
+                        error[xi + 1][ch] += err * 7.0 / 16.0;
+                        error[w + xi + 1][ch] += err / 16.0;
+                    }
+                    if xi > 0 {
+                        error[w + xi - 1][ch] += err * 3.0 / 16.0;
+                    }
+                    error[w + xi][ch] += err * 5.0 / 16.0;
+                }
+            }
+            
+            out.push(Some(result));
+        }
+    }
+    
+    out
+}
+
+/// Reduce an image to 15-bit color with the given dithering method.
+/// 
+/// Every pixel of the returned image holds a color exactly representable in a
+/// 15-bit hardware color, so direct color encoders will not need to round any
+/// further. Alpha values are carried over unchanged.
+pub fn dither_to_bgr555<I, P, S>(image: &I, mode: Dither) -> ImageBuffer<Rgba<u8>, Vec<u8>>
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    
+    let (width, height) = image.dimensions();
+    let colors = dither_pixels(image, mode, 255.0 / 31.0, |c| {
+        let reduced = reduce_to_bgr555(c);
+        (reduced, reduced)
+    });
+    
+    ImageBuffer::from_fn(width, height, |x, y| {
+        match colors[(y * width + x) as usize] {
+            Some(c) => c,
+            None => rgba8_from_pixel(image.get_pixel(x, y))
+        }
+    })
+}
+
+/// Estimate how far apart the colors of a palette typically are, for use as
+/// the spread of ordered dithering onto that palette.
+/// 
+/// This is the average distance from each opaque color to its nearest opaque
+/// neighbor. Index 0 is ignored as it is reserved for transparency.
+pub fn palette_spread(palette: &[Rgba<u8>]) -> f32 {
+    let opaque = if palette.len() > 1 { &palette[1..] } else { palette };
+    
+    if opaque.len() < 2 {
+        return 0.0;
+    }
+    
+    let mut total = 0f32;
+    
+    for (i, a) in opaque.iter().enumerate() {
+        let mut nearest = f32::MAX;
+        
+        for (j, b) in opaque.iter().enumerate() {
+            if i != j {
+                let dr = a[0] as f32 - b[0] as f32;
+                let dg = a[1] as f32 - b[1] as f32;
+                let db = a[2] as f32 - b[2] as f32;
+                nearest = nearest.min((dr * dr + dg * dg + db * db).sqrt());
+            }
+        }
+        
+        total += nearest;
+    }
+    
+    total / opaque.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::dither::{Dither, dither_to_bgr555};
+    use awsmimg::quantize::reduce_to_bgr555;
+    
+    #[test]
+    fn dither_none_rounds() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(8, 8, |x, y| Rgba([(x * 30) as u8, (y * 30) as u8, 77, 255]));
+        let out = dither_to_bgr555(&img, Dither::None);
+        
+        for (x, y, p) in out.enumerate_pixels() {
+            assert_eq!(*p, reduce_to_bgr555(*img.get_pixel(x, y)));
+        }
+    }
+    
+    #[test]
+    fn dither_preserves_average() {
+        //A flat color halfway between two 15-bit levels should dither to a
+        //mix of both levels rather than rounding every pixel the same way.
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(16, 16, Rgba([0x88u8, 0x88, 0x88, 255]));
+        
+        for mode in [Dither::Bayer, Dither::FloydSteinberg, Dither::TileStable].iter() {
+            let out = dither_to_bgr555(&img, *mode);
+            let total : u32 = out.pixels().map(|p| p[0] as u32).sum();
+            let avg = total as f32 / 256.0;
+            
+            assert!((avg - 136.0).abs() < 2.0, "{:?} averaged {}", mode, avg);
+            assert!(out.pixels().any(|p| p[0] != out.get_pixel(0, 0)[0]));
+        }
+    }
+    
+    #[test]
+    fn tile_stable_repeats() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_pixel(16, 16, Rgba([0x84u8, 0x40, 0x21, 255]));
+        let out = dither_to_bgr555(&img, Dither::TileStable);
+        
+        for (x, y, p) in out.enumerate_pixels() {
+            assert_eq!(p, out.get_pixel(x % 8, y % 8));
+        }
+    }
+}
@@ -2,9 +2,15 @@ use std::io;
 use std::io::Write;
 use image::{GenericImage, Primitive, Rgba, Pixel};
 
-use awsmimg::formats::{IndexedGraphicsProperties, IndexedFormat, DirectFormat};
-use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder, AGB16Encoder};
-use awsmimg::conversion::indexes_from_luma;
+use awsmimg::error::{Error, Result};
+use awsmimg::formats::{IndexedGraphicsProperties, TilemapProperties, DirectFormat};
+use awsmimg::formats::agb::AGB16Encoder;
+use awsmimg::conversion::{indexes_from_luma, indexes_from_palette};
+use awsmimg::quantize::quantize_image;
+use awsmimg::dither::Dither;
+use awsmimg::subpalettes::{assign_subpalettes, SubpaletteAssignment};
+use awsmimg::tiles::{MapEntry, deduplicate_tiles, join_tiles};
+use awsmimg::sprites::{ObjMapping, SpriteLayout, sprite_indexes_from_sheet};
 
 /// Represents a struct which can encode color indexes and their palettes into
 /// a particular indexed image format.
@@ -38,32 +44,125 @@ pub trait IndexedGraphicsEncoder : IndexedGraphicsProperties {
     fn encode_palette<T: Primitive>(&mut self, palette: Vec<Rgba<T>>) -> io::Result<()>;
 }
 
+/// Object-safe counterpart to IndexedGraphicsEncoder.
+///
+/// IndexedGraphicsEncoder cannot be made into a trait object because its
+/// methods are generic over the primitive type of the data. This trait fixes
+/// that type to u8 so that encoders can be boxed and selected at runtime. It
+/// is implemented for every IndexedGraphicsEncoder, and boxed encoders in turn
+/// implement IndexedGraphicsEncoder, so they may be passed to any of the
+/// encode_image_as_* functions.
+pub trait DynIndexedGraphicsEncoder : IndexedGraphicsProperties {
+    /// Encode 8-bit palette indexes. See IndexedGraphicsEncoder::encode_indexes.
+    fn encode_index_data(&mut self, data: Vec<u8>, width: u32, height: u32) -> io::Result<()>;
+    
+    /// Encode 8-bit RGBA colors. See IndexedGraphicsEncoder::encode_palette.
+    fn encode_palette_colors(&mut self, palette: Vec<Rgba<u8>>) -> io::Result<()>;
+}
+
+impl<T> DynIndexedGraphicsEncoder for T where T: IndexedGraphicsEncoder {
+    fn encode_index_data(&mut self, data: Vec<u8>, width: u32, height: u32) -> io::Result<()> {
+        self.encode_indexes(data, width, height)
+    }
+    
+    fn encode_palette_colors(&mut self, palette: Vec<Rgba<u8>>) -> io::Result<()> {
+        self.encode_palette(palette)
+    }
+}
+
+impl<'a> IndexedGraphicsProperties for Box<dyn DynIndexedGraphicsEncoder + 'a> {
+    fn tile_size(&self) -> (u32, u32) {
+        (**self).tile_size()
+    }
+    
+    fn attribute_size(&self) -> (u32, u32) {
+        (**self).attribute_size()
+    }
+    
+    fn palette_maxcol(&self) -> u16 {
+        (**self).palette_maxcol()
+    }
+    
+    fn bits_per_pixel(&self) -> u32 {
+        (**self).bits_per_pixel()
+    }
+}
+
+impl<'a> IndexedGraphicsEncoder for Box<dyn DynIndexedGraphicsEncoder + 'a> {
+    fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, width: u32, height: u32) -> io::Result<()> {
+        let data = data.into_iter().map(|index| index.to_u8().unwrap_or(u8::MAX)).collect();
+        (**self).encode_index_data(data, width, height)
+    }
+    
+    fn encode_palette<T: Primitive>(&mut self, palette: Vec<Rgba<T>>) -> io::Result<()> {
+        let maxval = T::max_value().to_f32().unwrap();
+        let palette = palette.into_iter().map(|color| {
+            let scale = |c: T| (c.to_f32().unwrap() / maxval * 255f32) as u8;
+            Rgba([scale(color[0]), scale(color[1]), scale(color[2]), scale(color[3])])
+        }).collect();
+        
+        (**self).encode_palette_colors(palette)
+    }
+}
+
 /// Given an image and an encoder, encode index data by interpreting the
 /// grayscale values of an image as indicies.
 /// 
 /// The grayscale-image-as-index-data approach is useful because it assigns an
 /// unambiguous color to every index, allowing editing of the graphical data
 /// using image manipulation tools that don't provide palette editing.
-pub fn encode_image_as_indexes<'a, E, I, P, S>(enc: &mut E, image: &I) -> io::Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
+pub fn encode_image_as_indexes<'a, E, I, P, S>(enc: &mut E, image: &I) -> Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
     let (width, height) = image.dimensions();
     
     let gdata = indexes_from_luma(image, S::from(enc.palette_maxcol()).unwrap(), enc.tile_size());
-    enc.encode_indexes(gdata, width, height)
+    Ok(enc.encode_indexes(gdata, width, height)?)
 }
 
-/// Given an image, a writer, and a format description, encode index data by
-/// interpreting the grayscale values of an image as indicies.
+/// Given an image, an encoder, and a palette, encode index data by mapping
+/// each pixel of the image to its matching color within the palette.
 /// 
-/// This function allows access to built-in, private type implementations of
-/// these traits. It is currently not possible to access these types through any
-/// other means as they are private and IndexedGraphicsEncoder cannot be
-/// dynamically dispatched.
-pub fn encode_image_as_indexes_with_format<'a, W, I, P, S>(format: IndexedFormat, w: &mut W, image: &I) -> io::Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, W: Write + 'a {
-    match format {
-        IndexedFormat::AGB4 => encode_image_as_indexes(&mut AGB4Encoder::new(w), image),
-        IndexedFormat::AGB8Tiled => encode_image_as_indexes(&mut AGB8Encoder::new_tiled(w), image),
-        IndexedFormat::AGB8Chunky => encode_image_as_indexes(&mut AGB8Encoder::new_chunky(w), image)
-    }
+/// Only the first palette_maxcol() + 1 colors of the palette can be encoded,
+/// since the format cannot represent any further indexes. Colors not present
+/// among them yield an error reporting the offending pixel, unless nearest is
+/// true and the color does not appear later in the palette either, in which
+/// case the closest encodable color will be used instead.
+pub fn encode_image_as_palette_indexes<'a, E, I, P, S>(enc: &mut E, image: &I, palette: &[Rgba<u8>], nearest: bool) -> Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
+    let (width, height) = image.dimensions();
+    
+    let gdata = indexes_from_palette(image, palette, enc.palette_maxcol(), enc.tile_size(), nearest)?;
+    Ok(enc.encode_indexes(gdata, width, height)?)
+}
+
+/// Given a truecolor image and an encoder, reduce the image to the number of
+/// colors the encoder's format supports and encode the resulting indexes.
+/// 
+/// The palette generated for the image is returned so that it may be encoded
+/// separately. Index 0 of that palette is reserved for transparency. Pixels are
+/// mapped onto the palette using the given dithering method; Dither::None maps
+/// every pixel to its nearest palette color.
+pub fn encode_image_as_quantized_indexes<'a, E, I, P, S>(enc: &mut E, image: &I, dither: Dither) -> Result<Vec<Rgba<u8>>> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
+    let (width, height) = image.dimensions();
+    
+    let quantized = quantize_image(image, enc.palette_maxcol(), enc.tile_size(), dither);
+    enc.encode_indexes(quantized.indexes, width, height)?;
+    
+    Ok(quantized.palette)
+}
+
+/// Given a truecolor image and an encoder, split the image into the format's
+/// attribute regions and encode indexes drawing from at most max_palettes
+/// sub-palettes.
+/// 
+/// The returned assignment describes which sub-palette each attribute region
+/// uses and holds the generated sub-palettes, so that they can be encoded
+/// separately.
+pub fn encode_image_as_subpalette_indexes<'a, E, I, P, S>(enc: &mut E, image: &I, max_palettes: usize) -> Result<SubpaletteAssignment> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
+    let (width, height) = image.dimensions();
+    
+    let result = assign_subpalettes(image, enc.palette_maxcol(), enc.tile_size(), enc.attribute_size(), max_palettes)?;
+    enc.encode_indexes(result.indexes, width, height)?;
+    
+    Ok(result.assignment)
 }
 
 /// Represents a struct which can encode color images into a particular direct
@@ -86,7 +185,9 @@ pub trait DirectGraphicsEncoder {
     /// 
     /// Graphics formats with lower bit depths must convert higher bit-depth
     /// images by rounding to the nearest neighbor and not by any other method.
-    /// In particular, dithering is not permitted.
+    /// In particular, encoders must not dither on their own. Callers that want
+    /// dithering must opt in by reducing the image beforehand, e.g. with
+    /// awsmimg::dither::dither_to_bgr555, so that no further rounding occurs.
     fn encode_colors<I, P, S>(&mut self, image: &I) -> io::Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static;
 }
 
@@ -97,9 +198,150 @@ pub trait DirectGraphicsEncoder {
 /// these traits. It is currently not possible to access these types through any
 /// other means as they are private and DirectGraphicsEncoder cannot be
 /// dynamically dispatched.
-pub fn encode_image_as_direct_color_with_format<'a, W, I, P, S>(format: DirectFormat, w: &mut W, image: &I) -> io::Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, W: Write + 'a {
+pub fn encode_image_as_direct_color_with_format<'a, W, I, P, S>(format: DirectFormat, w: &mut W, image: &I) -> Result<()> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, W: Write + 'a {
     match format {
-        DirectFormat::AGB16 => AGB16Encoder::new_agb(w).encode_colors(image),
-        DirectFormat::NTR16 => AGB16Encoder::new_ntr(w).encode_colors(image)
+        DirectFormat::AGB16 => Ok(AGB16Encoder::new_agb(w).encode_colors(image)?),
+        DirectFormat::NTR16 => Ok(AGB16Encoder::new_ntr(w).encode_colors(image)?)
     }
 }
+
+
+/// Represents a struct which can encode tilemaps into a particular map format.
+pub trait MapEncoder : TilemapProperties {
+    /// Given a list of map entries, encode them for the particular map format
+    /// this encoder supports.
+    /// 
+    /// The width and height values indicate the size of the map in tiles, and
+    /// map must always contain width * height entries in left-to-right,
+    /// top-to-bottom order. Map sizes the format cannot represent, and entries
+    /// referring to tiles, flips, or palette banks it cannot represent, must
+    /// result in Err rather than being truncated.
+    fn encode_map(&mut self, map: &[MapEntry], width: u32, height: u32) -> io::Result<()>;
+}
+
+/// Methods of interpreting an image's pixels as color indexes.
+pub enum IndexSource<'p> {
+    /// Interpret grayscale values as indexes, as encode_image_as_indexes does.
+    Luma,
+    
+    /// Find each pixel's color within a palette, optionally falling back to
+    /// the nearest color, as encode_image_as_palette_indexes does.
+    Palette(&'p [Rgba<u8>], bool),
+    
+    /// Generate a palette for the image, as encode_image_as_quantized_indexes
+    /// does.
+    Quantize(Dither),
+    
+    /// Generate up to this many sub-palettes, as
+    /// encode_image_as_subpalette_indexes does.
+    Subpalettes(usize)
+}
+
+/// Index data produced from an image, along with any palettes generated for
+/// it along the way.
+pub struct ImageIndexes {
+    pub indexes: Vec<u8>,
+    pub palette: Option<Vec<Rgba<u8>>>,
+    pub subpalettes: Option<SubpaletteAssignment>
+}
+
+/// Given an image, interpret it as tile-ordered index data for a format with
+/// the given properties.
+pub fn indexes_from_image<'a, F, I, P, S>(props: &F, image: &I, source: IndexSource<'a>) -> Result<ImageIndexes> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, F: IndexedGraphicsProperties + ?Sized {
+    let mut out = ImageIndexes {
+        indexes: Vec::new(),
+        palette: None,
+        subpalettes: None
+    };
+    
+    match source {
+        IndexSource::Luma => {
+            let gdata = indexes_from_luma(image, S::from(props.palette_maxcol()).unwrap(), props.tile_size());
+            out.indexes = gdata.into_iter().map(|i| i.to_u8().unwrap_or(u8::MAX)).collect();
+        },
+        IndexSource::Palette(palette, nearest) => {
+            out.indexes = indexes_from_palette(image, palette, props.palette_maxcol(), props.tile_size(), nearest)?;
+        },
+        IndexSource::Quantize(dither) => {
+            let quantized = quantize_image(image, props.palette_maxcol(), props.tile_size(), dither);
+            out.indexes = quantized.indexes;
+            out.palette = Some(quantized.palette);
+        },
+        IndexSource::Subpalettes(max_palettes) => {
+            let result = assign_subpalettes(image, props.palette_maxcol(), props.tile_size(), props.attribute_size(), max_palettes)?;
+            out.indexes = result.indexes;
+            out.palette = Some(result.assignment.flattened_palette());
+            out.subpalettes = Some(result.assignment);
+        }
+    }
+    
+    Ok(out)
+}
+
+/// Given an image, a tile encoder, and a map encoder, split the image into a
+/// set of unique tiles and a map that reassembles the image from them.
+/// 
+/// The image must be a whole number of tiles in size, and the resulting map
+/// must be a size the map format supports. Tiles are deduplicated, including
+/// mirrored copies if the map format can flip tiles. If the index source
+/// generates sub-palettes, each map entry selects its tile's sub-palette.
+/// 
+/// Any palette generated for the image is returned so that it may be encoded
+/// separately.
+pub fn encode_image_as_tilemap<'a, 'p, E, M, I, P, S>(enc: &mut E, mapenc: &mut M, image: &I, source: IndexSource<'p>) -> Result<Option<Vec<Rgba<u8>>>> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a, M: MapEncoder + 'a {
+    let (width, height) = image.dimensions();
+    let (tw, th) = enc.tile_size();
+    
+    if width % tw != 0 || height % th != 0 {
+        return Err(Error::BadDimensions { width, height, multiple: (tw, th) });
+    }
+    
+    if !mapenc.supports_tiles((tw, th), enc.palette_maxcol()) {
+        return Err(Error::invalid(format!("This map format cannot display {}x{} tiles with {} colors", tw, th, enc.palette_maxcol() as u32 + 1)));
+    }
+    
+    let (mw, mh) = (width / tw, height / th);
+    
+    if !mapenc.valid_map_size(mw, mh) {
+        return Err(Error::invalid(format!("A {}x{} tile map is not supported by this map format", mw, mh)));
+    }
+    
+    let mut data = indexes_from_image(enc, image, source)?;
+    data.indexes.resize((width * height) as usize, 0);
+    
+    let mut tileset = deduplicate_tiles(&data.indexes, (tw, th), mapenc.allows_flips());
+    
+    if tileset.tiles.len() > mapenc.max_tile() + 1 {
+        return Err(Error::invalid(format!("Image needs {} unique tiles, but this map format can only refer to {}", tileset.tiles.len(), mapenc.max_tile() + 1)));
+    }
+    
+    if let Some(ref assignment) = data.subpalettes {
+        for (entry, bank) in tileset.map.iter_mut().zip(assignment.tile_banks((tw, th), (width, height))) {
+            entry.palette = bank;
+        }
+    }
+    
+    let tcount = tileset.tiles.len() as u32;
+    enc.encode_indexes(join_tiles(&tileset.tiles), tw, th * tcount)?;
+    mapenc.encode_map(&tileset.map, mw, mh)?;
+    
+    Ok(data.palette)
+}
+
+/// Given a sprite sheet and an encoder, encode each frame of the sheet in the
+/// tile order sprite memory uses for the given sprite size and mapping mode.
+/// 
+/// Frames are taken from the sheet left-to-right, top-to-bottom, and the sheet
+/// must be a whole number of frames in size. Any palette generated for the
+/// image is returned so that it may be encoded separately.
+pub fn encode_image_as_sprites<'a, 'p, E, I, P, S>(enc: &mut E, image: &I, source: IndexSource<'p>, obj_size: (u32, u32), mapping: ObjMapping) -> Result<Option<Vec<Rgba<u8>>>> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, E: IndexedGraphicsEncoder + 'a {
+    let layout = SpriteLayout::new(obj_size, mapping, enc)?;
+    let data = indexes_from_image(enc, image, source)?;
+    let sprites = sprite_indexes_from_sheet(&data.indexes, image.dimensions(), &layout)?;
+    let (tw, th) = layout.tsize;
+    let tcount = sprites.len() as u32 / (tw * th);
+    
+    enc.encode_indexes(sprites, tw, th * tcount)?;
+    
+    Ok(data.palette)
+}
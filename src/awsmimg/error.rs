@@ -0,0 +1,142 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::result;
+use image::ImageError;
+
+/// Everything that can go wrong while converting images to or from retro
+/// formats.
+///
+/// Encoder and decoder traits still speak io::Result, as they sit on top of
+/// readers and writers. An Error returned through them is carried inside the
+/// io::Error and recovered intact when converted back with From.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying reader or writer failed, or ran out of data.
+    Io(io::Error),
+    
+    /// No format of the given kind goes by this name.
+    UnknownFormat(String),
+    
+    /// An image is not a whole number of the units it must be built from,
+    /// such as tiles or sprites.
+    BadDimensions {
+        width: u32,
+        height: u32,
+        multiple: (u32, u32)
+    },
+    
+    /// The pixel at the given coordinates, of the given color, cannot be
+    /// assigned an index the format can store. It is the first of count such
+    /// pixels.
+    IndexOutOfRange {
+        x: u32,
+        y: u32,
+        color: [u8; 3],
+        count: usize
+    },
+    
+    /// Encoded data is malformed at the given offset within its stream.
+    CorruptData {
+        offset: u64,
+        reason: String
+    },
+    
+    /// Encoded data does not fit in the space allotted to it.
+    TooLarge {
+        size: u64,
+        limit: u64
+    },
+    
+    /// The request cannot be carried out as given, e.g. because the format
+    /// does not support it.
+    InvalidInput(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Shorthand for building an InvalidInput error from any message.
+    pub fn invalid<S: Into<String>>(msg: S) -> Error {
+        Error::InvalidInput(msg.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => e.fmt(f),
+            Error::UnknownFormat(ref name) => write!(f, "Unknown format: {}", name),
+            Error::BadDimensions { width, height, multiple: (mw, mh) } => write!(f, "A {}x{} image is not a multiple of {}x{}", width, height, mw, mh),
+            Error::IndexOutOfRange { x, y, color, count } => write!(f, "Pixel at ({}, {}) has color #{:02X}{:02X}{:02X}, which has no index in the palette ({} unmatched pixels in total)", x, y, color[0], color[1], color[2], count),
+            Error::CorruptData { offset, ref reason } => write!(f, "Corrupt data at offset 0x{:X}: {}", offset, reason),
+            Error::TooLarge { size, limit } => write!(f, "Encoded data is {} bytes, {} bytes over the limit of {}", size, size - limit, limit),
+            Error::InvalidInput(ref msg) => f.write_str(msg)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e {
+            Error::Io(e) => return e,
+            Error::IndexOutOfRange { .. } | Error::CorruptData { .. } => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::InvalidInput
+        };
+        
+        io::Error::new(kind, e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Error {
+        match e {
+            ImageError::IoError(e) => Error::from(e),
+            ImageError::UnsupportedError(name) => Error::UnknownFormat(name),
+            e => Error::InvalidInput(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn io_roundtrip() {
+        let original = Error::CorruptData { offset: 0x20, reason: "bad tree".to_string() };
+        let carried : io::Error = original.into();
+        assert_eq!(carried.kind(), io::ErrorKind::InvalidData);
+        
+        match Error::from(carried) {
+            Error::CorruptData { offset, reason } => {
+                assert_eq!(offset, 0x20);
+                assert_eq!(reason, "bad tree");
+            },
+            e => panic!("Unexpected error {:?}", e)
+        }
+        
+        let plain = Error::from(io::Error::new(io::ErrorKind::UnexpectedEof, "short"));
+        assert!(match plain { Error::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof, _ => false });
+        assert_eq!(Error::BadDimensions { width: 12, height: 8, multiple: (8, 8) }.to_string(), "A 12x8 image is not a multiple of 8x8");
+    }
+}
@@ -1,13 +1,13 @@
 use awsmimg::formats::IndexedGraphicsProperties;
 use awsmimg::encoder::{IndexedGraphicsEncoder, DirectGraphicsEncoder};
-use awsmimg::decoder::IndexedGraphicsDecoder;
+use awsmimg::decoder::{IndexedGraphicsDecoder, DirectGraphicsDecoder};
 
 use std::io;
 use std::io::{Write, Read, ErrorKind};
-use image::{GenericImage, Primitive, Rgba, Pixel};
+use image::{GenericImage, ImageBuffer, Primitive, Rgba, Pixel};
 
 /// Encode a series of RGBA colors as palette data.
-fn encode_palette<'a, I: Iterator, T: Primitive, W: Write + 'a>(w: &'a mut W, palette: I, use_alpha: bool) -> io::Result<()> where I: Iterator<Item=Rgba<T>> {
+pub fn encode_palette<'a, I: Iterator, T: Primitive, W: Write + ?Sized + 'a>(w: &'a mut W, palette: I, use_alpha: bool) -> io::Result<()> where I: Iterator<Item=Rgba<T>> {
     let imgmax = T::max_value();
     let mut out: [u8; 2] = [0, 0];
 
@@ -30,6 +30,34 @@ fn encode_palette<'a, I: Iterator, T: Primitive, W: Write + 'a>(w: &'a mut W, pa
     Ok(())
 }
 
+/// Decode a number of palette entries previously written by encode_palette.
+/// 
+/// Each 5-bit color channel is expanded to 8 bits by replicating its upper
+/// bits, so that full intensity decodes to 255. The alpha bit is honored only
+/// if use_alpha is set; otherwise all decoded colors are opaque.
+pub fn decode_palette<'a, R: Read + ?Sized + 'a>(r: &'a mut R, count: usize, use_alpha: bool) -> io::Result<Vec<Rgba<u8>>> {
+    let mut out = Vec::with_capacity(count);
+    let mut buf: [u8; 2] = [0, 0];
+    
+    for _ in 0..count {
+        r.read_exact(&mut buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, "File is shorter than palette being decoded"),
+            _ => e
+        })?;
+        
+        let enc_color = (buf[0] as u16) | (buf[1] as u16) << 8;
+        let expand = |c: u16| -> u8 { ((c & 0x1F) << 3 | (c & 0x1F) >> 2) as u8 };
+        let a = match use_alpha {
+            true if enc_color & 0x8000 == 0 => 0,
+            _ => 255
+        };
+        
+        out.push(Rgba([expand(enc_color), expand(enc_color >> 5), expand(enc_color >> 10), a]));
+    }
+    
+    Ok(out)
+}
+
 struct ImageRgbaIterator<'a, I, P, S> where I: Iterator<Item=(u32, u32, P)> + 'a, P: Pixel<Subpixel=S> + 'a, S: Primitive + 'a {
     i: &'a mut I
 }
@@ -54,11 +82,11 @@ impl<'a, I, P, S> Iterator for ImageRgbaIterator<'a, I, P, S> where I: Iterator<
 }
 
 /// Encoder/decoder for 4bpp tile patterns for the AGB platform.
-pub struct AGB4Encoder<'a, F: 'a> {
+pub struct AGB4Encoder<'a, F: 'a + ?Sized> {
     f: &'a mut F,
 }
 
-impl<'a, F: 'a> AGB4Encoder<'a, F> {
+impl<'a, F: 'a + ?Sized> AGB4Encoder<'a, F> {
     pub fn new(file: &'a mut F) -> AGB4Encoder<'a, F> {
         AGB4Encoder {
             f: file
@@ -66,7 +94,7 @@ impl<'a, F: 'a> AGB4Encoder<'a, F> {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsProperties for AGB4Encoder<'a, F> {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsProperties for AGB4Encoder<'a, F> {
     fn tile_size(&self) -> (u32, u32) {
         (8, 8)
     }
@@ -80,12 +108,14 @@ impl<'a, F: 'a> IndexedGraphicsProperties for AGB4Encoder<'a, F> {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsEncoder for AGB4Encoder<'a, F> where F: Write {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsEncoder for AGB4Encoder<'a, F> where F: Write {
     fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, width: u32, _height: u32) -> io::Result<()> {
         let mut out: [u8; 1] = [0];
         
+        //An odd pixel out shares its byte with a 0 in the high nibble.
         for byte in data.chunks(2) {
-            out[0] = byte[0].to_u8().unwrap() & 0x0F | (byte[1].to_u8().unwrap() & 0x0F) << 4;
+            let high = byte.get(1).map_or(0, |p| p.to_u8().unwrap());
+            out[0] = byte[0].to_u8().unwrap() & 0x0F | (high & 0x0F) << 4;
             self.f.write(&out)?;
         }
         
@@ -97,33 +127,34 @@ impl<'a, F: 'a> IndexedGraphicsEncoder for AGB4Encoder<'a, F> where F: Write {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsDecoder for AGB4Encoder<'a, F> where F: Read {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsDecoder for AGB4Encoder<'a, F> where F: Read {
     fn decode_indexes<P: Primitive>(&mut self, size: usize) -> io::Result<Vec<P>> {
-        let mut out = Vec::new();
-        let mut buf: [u8; 1] = [0];
+        let mut data = Vec::new();
+        Read::take(&mut *self.f, size.div_ceil(2) as u64).read_to_end(&mut data)?;
         
-        for i in 0..size {
-            let readcnt = self.f.read(&mut buf)?;
-            
-            if readcnt < 1 {
-                return Err(io::Error::new(ErrorKind::UnexpectedEof, "File is shorter than image being decoded"));
-            }
+        let mut out = Vec::with_capacity(data.len() * 2);
             
-            out.push(P::from(buf[0] & 0x0F).unwrap());
-            out.push(P::from(buf[0] >> 4).unwrap());
+        for byte in data {
+            out.push(P::from(byte & 0x0F).unwrap());
+            out.push(P::from(byte >> 4).unwrap());
         }
         
+        out.truncate(size);
         Ok(out)
     }
+    
+    fn decode_palette(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>> {
+        decode_palette(self.f, count, false)
+    }
 }
 
 /// Encoder for 8bpp tile patterns for the AGB platform.
-pub struct AGB8Encoder<'a, F: 'a> {
+pub struct AGB8Encoder<'a, F: 'a + ?Sized> {
     f: &'a mut F,
     tsize: u32
 }
 
-impl<'a, F: 'a> AGB8Encoder<'a, F> {
+impl<'a, F: 'a + ?Sized> AGB8Encoder<'a, F> {
     pub fn new_tiled(file: &'a mut F) -> AGB8Encoder<'a, F> {
         AGB8Encoder {
             f: file,
@@ -139,7 +170,7 @@ impl<'a, F: 'a> AGB8Encoder<'a, F> {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsProperties for AGB8Encoder<'a, F> {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsProperties for AGB8Encoder<'a, F> {
     fn tile_size(&self) -> (u32, u32) {
         (self.tsize, self.tsize)
     }
@@ -153,7 +184,7 @@ impl<'a, F: 'a> IndexedGraphicsProperties for AGB8Encoder<'a, F> {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsEncoder for AGB8Encoder<'a, F> where F: Write {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsEncoder for AGB8Encoder<'a, F> where F: Write {
     fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, width: u32, _height: u32) -> io::Result<()> {
         let mut out: [u8; 1] = [0];
         
@@ -170,31 +201,26 @@ impl<'a, F: 'a> IndexedGraphicsEncoder for AGB8Encoder<'a, F> where F: Write {
     }
 }
 
-impl<'a, F: 'a> IndexedGraphicsDecoder for AGB8Encoder<'a, F> where F: Read {
+impl<'a, F: 'a + ?Sized> IndexedGraphicsDecoder for AGB8Encoder<'a, F> where F: Read {
     fn decode_indexes<P: Primitive>(&mut self, size: usize) -> io::Result<Vec<P>> {
-        let mut out = Vec::with_capacity(size);
-        let mut buf: [u8; 1] = [0];
+        let mut data = Vec::new();
+        Read::take(&mut *self.f, size as u64).read_to_end(&mut data)?;
         
-        for i in 0..size {
-            let readcnt = self.f.read(&mut buf)?;
-            
-            if readcnt < 1 {
-                return Err(io::Error::new(ErrorKind::UnexpectedEof, "File is shorter than image being decoded"));
-            }
+        Ok(data.into_iter().map(|byte| P::from(byte).unwrap()).collect())
+    }
             
-            out.push(P::from(buf[0]).unwrap());
-        }
-        
-        Ok(out)
+    fn decode_palette(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>> {
+        decode_palette(self.f, count, false)
     }
 }
 
-pub struct AGB16Encoder<'a, W: Write + 'a> {
+/// Encoder/decoder for 16bpp direct color data for the AGB and NTR platforms.
+pub struct AGB16Encoder<'a, W: 'a> {
     w: &'a mut W,
     allow_ntr_alpha: bool
 }
 
-impl<'a, W: Write + 'a> AGB16Encoder<'a, W> {
+impl<'a, W: 'a> AGB16Encoder<'a, W> {
     pub fn new_agb(write: &'a mut W) -> AGB16Encoder<'a, W> {
         AGB16Encoder {
             w: write,
@@ -216,6 +242,17 @@ impl<'a, W: Write> DirectGraphicsEncoder for AGB16Encoder<'a, W> {
     }
 }
 
+impl<'a, R: Read> DirectGraphicsDecoder for AGB16Encoder<'a, R> {
+    fn decode_colors(&mut self, width: u32, height: u32) -> io::Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let colors = decode_palette(self.w, (width * height) as usize, self.allow_ntr_alpha).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, "File is shorter than image being decoded"),
+            _ => e
+        })?;
+        
+        Ok(ImageBuffer::from_fn(width, height, |x, y| colors[(y * width + x) as usize]))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate num;
@@ -223,7 +260,7 @@ mod tests {
     
     use std::io::Cursor;
     use awsmimg::encoder::{IndexedGraphicsEncoder, DirectGraphicsEncoder};
-    use awsmimg::decoder::IndexedGraphicsDecoder;
+    use awsmimg::decoder::{IndexedGraphicsDecoder, DirectGraphicsDecoder};
     use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder, AGB16Encoder};
     
     #[test]
@@ -254,7 +291,7 @@ mod tests {
         let mut test_in = Cursor::new(&src);
         let mut agb4 = AGB4Encoder::new(&mut test_in);
         
-        let test_out : Vec<u8> = agb4.decode_indexes(src.len()).unwrap();
+        let test_out : Vec<u8> = agb4.decode_indexes(src.len() * 2).unwrap();
         let valid_out : Vec<u8> = vec![0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,
                                        0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,
                                        0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,
@@ -263,6 +300,17 @@ mod tests {
         assert_eq!(&test_out, &valid_out)
     }
     
+    #[test]
+    fn data4_decode_limits() {
+        let src : Vec<u8> = vec![0x10, 0x32, 0x54, 0x76];
+        
+        let short : Vec<u8> = AGB4Encoder::new(&mut Cursor::new(&src)).decode_indexes(3).unwrap();
+        assert_eq!(short, vec![0, 1, 2]);
+        
+        let all : Vec<u8> = AGB4Encoder::new(&mut Cursor::new(&src)).decode_indexes(usize::MAX).unwrap();
+        assert_eq!(all, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+    
     #[test]
     fn data8t_encode() {
         let src = num::range(0, 64).collect();
@@ -304,7 +352,30 @@ mod tests {
         let test_out : Vec<u8> = agb8.decode_indexes(src.len()).unwrap();
         let valid_out : Vec<u8> = num::range(0, 64).collect();
         
-        assert_eq!(&test_out, &valid_out)
+        assert_eq!(&test_out, &valid_out);
+        
+        let all : Vec<u8> = AGB8Encoder::new_tiled(&mut Cursor::new(&src)).decode_indexes(usize::MAX).unwrap();
+        assert_eq!(&all, &valid_out)
+    }
+    
+    #[test]
+    fn palette_roundtrip() {
+        let palette = vec![image::Rgba([0u8, 0, 0, 255]), image::Rgba([255u8, 255, 255, 255]),
+                           image::Rgba([255u8, 0, 0, 255]), image::Rgba([0x84u8, 0x10, 0xFF, 255])];
+        let mut test_out = Cursor::new(Vec::with_capacity(8));
+        
+        {
+            let mut agb4 = AGB4Encoder::new(&mut test_out);
+            
+            agb4.encode_palette(palette.clone()).unwrap();
+        }
+        
+        assert_eq!(test_out.get_ref(), &vec![0x00, 0x00, 0xFF, 0x7F, 0x1F, 0x00, 0x50, 0x7C]);
+        
+        test_out.set_position(0);
+        let mut agb4 = AGB4Encoder::new(&mut test_out);
+        
+        assert_eq!(agb4.decode_palette(4).unwrap(), palette);
     }
     
     #[test]
@@ -320,7 +391,6 @@ mod tests {
             agb16.encode_colors(&img);
         }
         
-        //TODO: Actually round-trip this against an AGB16 decoder (not yet written)
         //This vector was obtained by grabbing some valid-looking output from
         //the code under test and spot-checking a few values against the above
         let valid_out : Vec<u8> = vec![224, 3, 225, 3, 226, 3, 227, 3, 228, 3, 229, 3, 230, 3, 231, 3,
@@ -332,6 +402,14 @@ mod tests {
                                        32, 3, 33, 3, 34, 7, 35, 11, 36, 15, 37, 15, 38, 19, 39, 23,
                                        0, 3, 1, 3, 2, 7, 3, 11, 4, 15, 5, 19, 6, 23, 7, 27];
         
-        assert_eq!(test_out.get_ref(), &valid_out)
+        assert_eq!(test_out.get_ref(), &valid_out);
+        
+        test_out.set_position(0);
+        let decoded = AGB16Encoder::new_agb(&mut test_out).decode_colors(8, 8).unwrap();
+        let expand = |c: u8| (c & 0xF8) | c >> 5;
+        
+        for (x, y, pixel) in img.enumerate_pixels() {
+            assert_eq!(*decoded.get_pixel(x, y), image::Rgba([expand(pixel[0]), expand(pixel[1]), expand(pixel[2]), 255]));
+        }
     }
 }
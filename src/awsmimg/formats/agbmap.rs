@@ -0,0 +1,259 @@
+use awsmimg::formats::{TilemapProperties, MapFormat};
+use awsmimg::encoder::MapEncoder;
+use awsmimg::decoder::MapDecoder;
+use awsmimg::tiles::MapEntry;
+
+use std::io;
+use std::io::{Write, Read, ErrorKind};
+
+/// Determine where a map cell is stored within a GBA text background map.
+/// 
+/// Text backgrounds are stored as a series of 32x32 entry screenblocks. Maps
+/// 64 tiles wide place the right half of the map in the following screenblock,
+/// and maps 64 tiles high place the bottom half after all of the top half's
+/// screenblocks.
+fn text_map_position(x: u32, y: u32, width: u32) -> usize {
+    let screenblock = (y / 32) * (width / 32) + x / 32;
+    
+    (screenblock * 1024 + (y % 32) * 32 + x % 32) as usize
+}
+
+/// Encoder/decoder for tilemaps of backgrounds on the AGB platform.
+/// 
+/// Regular (text) backgrounds use 16-bit map entries which can flip tiles and
+/// select one of 16 palette banks. Affine (rotation/scaling) backgrounds use
+/// 8-bit map entries holding only a tile number, and always use 8bpp tiles.
+pub struct AGBMapEncoder<'a, F: 'a> {
+    f: &'a mut F,
+    affine: bool
+}
+
+impl<'a, F: 'a> AGBMapEncoder<'a, F> {
+    pub fn new(file: &'a mut F, format: MapFormat) -> AGBMapEncoder<'a, F> {
+        match format {
+            MapFormat::AGBText => AGBMapEncoder::new_text(file),
+            MapFormat::AGBAffine => AGBMapEncoder::new_affine(file)
+        }
+    }
+    
+    pub fn new_text(file: &'a mut F) -> AGBMapEncoder<'a, F> {
+        AGBMapEncoder {
+            f: file,
+            affine: false
+        }
+    }
+    
+    pub fn new_affine(file: &'a mut F) -> AGBMapEncoder<'a, F> {
+        AGBMapEncoder {
+            f: file,
+            affine: true
+        }
+    }
+    
+    fn kind(&self) -> &'static str {
+        match self.affine {
+            true => "affine",
+            false => "text"
+        }
+    }
+    
+    fn entry_size(&self) -> usize {
+        match self.affine {
+            true => 1,
+            false => 2
+        }
+    }
+    
+    /// Determine where a map cell is stored, in entries.
+    fn position(&self, x: u32, y: u32, width: u32) -> usize {
+        match self.affine {
+            true => (y * width + x) as usize,
+            false => text_map_position(x, y, width)
+        }
+    }
+    
+    fn size_error(&self, width: u32, height: u32) -> io::Error {
+        let hint = match self.affine {
+            true => "use a square of 128, 256, 512, or 1024 pixels",
+            false => "use 256 or 512 pixels on each side"
+        };
+        
+        io::Error::new(ErrorKind::InvalidInput, format!("A {}x{} tile map is not a valid {} background size; {}", width, height, self.kind(), hint))
+    }
+}
+
+impl<'a, F: 'a> TilemapProperties for AGBMapEncoder<'a, F> {
+    fn max_tile(&self) -> usize {
+        match self.affine {
+            true => 255,
+            false => 1023
+        }
+    }
+    
+    fn allows_flips(&self) -> bool {
+        !self.affine
+    }
+    
+    fn max_palette_bank(&self) -> u8 {
+        match self.affine {
+            true => 0,
+            false => 15
+        }
+    }
+    
+    fn valid_map_size(&self, width: u32, height: u32) -> bool {
+        match self.affine {
+            true => width == height && (width == 16 || width == 32 || width == 64 || width == 128),
+            false => (width == 32 || width == 64) && (height == 32 || height == 64)
+        }
+    }
+    
+    fn supports_tiles(&self, tsize: (u32, u32), maxcol: u16) -> bool {
+        match self.affine {
+            true => tsize == (8, 8) && maxcol == 255,
+            false => tsize == (8, 8) && (maxcol == 15 || maxcol == 255)
+        }
+    }
+}
+
+impl<'a, F: 'a> MapEncoder for AGBMapEncoder<'a, F> where F: Write {
+    fn encode_map(&mut self, map: &[MapEntry], width: u32, height: u32) -> io::Result<()> {
+        if !self.valid_map_size(width, height) || map.len() != (width * height) as usize {
+            return Err(self.size_error(width, height));
+        }
+        
+        let entry_size = self.entry_size();
+        let mut out = vec![0u8; map.len() * entry_size];
+        
+        for (i, entry) in map.iter().enumerate() {
+            if entry.tile > self.max_tile() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("Map cell {} refers to tile {}, but {} backgrounds can only use {} tiles", i, entry.tile, self.kind(), self.max_tile() + 1)));
+            }
+            
+            if entry.palette > self.max_palette_bank() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("Map cell {} uses palette bank {}, but {} backgrounds only have {}", i, entry.palette, self.kind(), self.max_palette_bank() as u32 + 1)));
+            }
+            
+            if (entry.hflip || entry.vflip) && !self.allows_flips() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("Map cell {} flips its tile, but {} backgrounds cannot flip tiles", i, self.kind())));
+            }
+            
+            let value = entry.tile as u16 | (entry.hflip as u16) << 10 | (entry.vflip as u16) << 11 | (entry.palette as u16) << 12;
+            let pos = self.position(i as u32 % width, i as u32 / width, width) * entry_size;
+            
+            out[pos] = (value & 0xFF) as u8;
+            if entry_size > 1 {
+                out[pos + 1] = (value >> 8) as u8;
+            }
+        }
+        
+        self.f.write_all(&out)
+    }
+}
+
+impl<'a, F: 'a> MapDecoder for AGBMapEncoder<'a, F> where F: Read {
+    fn decode_map(&mut self, width: u32, height: u32) -> io::Result<Vec<MapEntry>> {
+        if !self.valid_map_size(width, height) {
+            return Err(self.size_error(width, height));
+        }
+        
+        let entry_size = self.entry_size();
+        let mut raw = vec![0u8; (width * height) as usize * entry_size];
+        self.f.read_exact(&mut raw).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, "File is shorter than map being decoded"),
+            _ => e
+        })?;
+        
+        let mut out = Vec::with_capacity((width * height) as usize);
+        
+        for y in 0..height {
+            for x in 0..width {
+                let pos = self.position(x, y, width) * entry_size;
+                let value = match entry_size {
+                    1 => raw[pos] as u16,
+                    _ => raw[pos] as u16 | (raw[pos + 1] as u16) << 8
+                };
+                
+                out.push(MapEntry {
+                    tile: (value & 0x3FF) as usize,
+                    hflip: value & 0x400 != 0,
+                    vflip: value & 0x800 != 0,
+                    palette: (value >> 12) as u8
+                });
+            }
+        }
+        
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use awsmimg::encoder::MapEncoder;
+    use awsmimg::decoder::MapDecoder;
+    use awsmimg::formats::agbmap::AGBMapEncoder;
+    use awsmimg::tiles::MapEntry;
+    
+    #[test]
+    fn text_map_roundtrip() {
+        let map : Vec<MapEntry> = (0..64 * 32).map(|i| MapEntry {
+            tile: i % 1024,
+            hflip: i % 3 == 0,
+            vflip: i % 5 == 0,
+            palette: (i % 16) as u8
+        }).collect();
+        let mut test_out = Cursor::new(Vec::new());
+        
+        AGBMapEncoder::new_text(&mut test_out).encode_map(&map, 64, 32).unwrap();
+        
+        //Cell (32, 0) is the first entry of the second screenblock.
+        let raw = test_out.get_ref();
+        assert_eq!(raw.len(), 4096);
+        assert_eq!(&raw[0..2], &[0x00, 0x0C]);
+        assert_eq!(&raw[2048..2050], &[0x20, 0x00]);
+        
+        test_out.set_position(0);
+        assert_eq!(AGBMapEncoder::new_text(&mut test_out).decode_map(64, 32).unwrap(), map);
+    }
+    
+    #[test]
+    fn affine_map_roundtrip() {
+        let map : Vec<MapEntry> = (0..16 * 16).map(|i| MapEntry { tile: 255 - i, hflip: false, vflip: false, palette: 0 }).collect();
+        let mut test_out = Cursor::new(Vec::new());
+        
+        AGBMapEncoder::new_affine(&mut test_out).encode_map(&map, 16, 16).unwrap();
+        
+        let valid_out : Vec<u8> = (0..256).map(|i| (255 - i) as u8).collect();
+        assert_eq!(test_out.get_ref(), &valid_out);
+        
+        test_out.set_position(0);
+        assert_eq!(AGBMapEncoder::new_affine(&mut test_out).decode_map(16, 16).unwrap(), map);
+    }
+    
+    #[test]
+    fn affine_map_limits() {
+        let mut test_out = Cursor::new(Vec::new());
+        let mut map = vec![MapEntry { tile: 0, hflip: false, vflip: false, palette: 0 }; 16 * 16];
+        
+        assert!(AGBMapEncoder::new_affine(&mut test_out).encode_map(&map[..], 32, 8).is_err());
+        
+        map[3].tile = 256;
+        assert!(AGBMapEncoder::new_affine(&mut test_out).encode_map(&map, 16, 16).is_err());
+        
+        map[3].tile = 3;
+        map[4].hflip = true;
+        assert!(AGBMapEncoder::new_affine(&mut test_out).encode_map(&map, 16, 16).is_err());
+    }
+    
+    #[test]
+    fn text_map_limits() {
+        let mut test_out = Cursor::new(Vec::new());
+        let mut map = vec![MapEntry { tile: 0, hflip: false, vflip: false, palette: 0 }; 32 * 32];
+        
+        assert!(AGBMapEncoder::new_text(&mut test_out).encode_map(&map[..30 * 20], 30, 20).is_err());
+        
+        map[5].tile = 1024;
+        assert!(AGBMapEncoder::new_text(&mut test_out).encode_map(&map, 32, 32).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use awsmimg::error::{Error, Result};
+use awsmimg::formats::IndexedGraphicsProperties;
+use awsmimg::formats::agb::{encode_palette, decode_palette};
+use awsmimg::encoder::IndexedGraphicsEncoder;
+use awsmimg::decoder::IndexedGraphicsDecoder;
+
+use std::io;
+use std::io::{Write, Read};
+use std::fs::OpenOptions;
+use image::{Primitive, Rgba};
+use toml;
+
+/// How the bits of each pixel are arranged.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Packing {
+    Packed, //All bits of a pixel are stored together
+    Planar  //Each bit of a pixel is stored in a separate bitplane
+}
+
+/// Which end of a word the first pixel (or bitplane bit) is stored in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BitOrder {
+    Msb,
+    Lsb
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    Little,
+    Big
+}
+
+fn default_tile_size() -> (u32, u32) {
+    (8, 8)
+}
+
+fn default_packing() -> Packing {
+    Packing::Packed
+}
+
+fn default_bit_order() -> BitOrder {
+    BitOrder::Msb
+}
+
+fn default_word_size() -> u32 {
+    1
+}
+
+fn default_endianness() -> Endianness {
+    Endianness::Little
+}
+
+/// A declarative description of an indexed tile format.
+///
+/// Tiles are stored one after another. Within a tile, pixels are visited
+/// left-to-right, top-to-bottom, and their bits emitted into a stream of
+/// words, filling each word from the end given by bit_order:
+///
+///  * Packed layouts emit all bits_per_pixel bits of each pixel in turn.
+///  * Planar layouts split the tile's bitplanes into groups of plane_group
+///    planes. For each group, every row of the tile is emitted one plane at a
+///    time, one bit per pixel. A plane_group of 1 stores each plane as a whole
+///    tile (NES), one equal to bits_per_pixel interleaves every plane by row
+///    (Game Boy, Master System), and 2 interleaves pairs of planes (SNES).
+///
+/// Words are word_size bytes long and stored with the given endianness. Non-
+/// tiled formats use a tile_size of (1, 1), as with AGB8Chunky. Palettes are
+/// stored as BGR555, as with the AGB formats.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+pub struct BitLayout {
+    pub name: String,
+    
+    #[serde(default)]
+    pub description: String,
+    
+    pub bits_per_pixel: u32,
+    
+    #[serde(default = "default_tile_size")]
+    pub tile_size: (u32, u32),
+    
+    /// The size of a color attribute region. Defaults to the tile size.
+    #[serde(default)]
+    pub attribute_size: Option<(u32, u32)>,
+    
+    #[serde(default = "default_packing")]
+    pub packing: Packing,
+    
+    /// How many bitplanes are interleaved by row. Defaults to all of them.
+    #[serde(default)]
+    pub plane_group: Option<u32>,
+    
+    #[serde(default = "default_bit_order")]
+    pub bit_order: BitOrder,
+    
+    #[serde(default = "default_word_size")]
+    pub word_size: u32,
+    
+    #[serde(default = "default_endianness")]
+    pub endianness: Endianness
+}
+
+#[derive(Deserialize)]
+struct LayoutFile {
+    #[serde(default)]
+    layout: Vec<BitLayout>
+}
+
+impl BitLayout {
+    fn plane_group(&self) -> u32 {
+        self.plane_group.unwrap_or(self.bits_per_pixel)
+    }
+    
+    fn word_bits(&self) -> usize {
+        self.word_size as usize * 8
+    }
+    
+    /// The number of bits a single tile occupies.
+    pub fn tile_bits(&self) -> usize {
+        (self.tile_size.0 * self.tile_size.1 * self.bits_per_pixel) as usize
+    }
+    
+    /// Check that the layout describes a format that can actually be stored.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |why: &str| Err(Error::invalid(format!("Layout {} is invalid: {}", self.name, why)));
+        
+        if self.name.is_empty() {
+            return invalid("it has no name");
+        }
+        
+        if self.bits_per_pixel == 0 || self.bits_per_pixel > 8 {
+            return invalid("bits_per_pixel must be between 1 and 8");
+        }
+        
+        if self.tile_size.0 == 0 || self.tile_size.1 == 0 {
+            return invalid("tile_size must not be zero");
+        }
+        
+        if self.word_size != 1 && self.word_size != 2 && self.word_size != 4 {
+            return invalid("word_size must be 1, 2, or 4");
+        }
+        
+        if self.packing == Packing::Packed && !(self.word_bits() as u32).is_multiple_of(self.bits_per_pixel) {
+            return invalid("packed pixels must not straddle words");
+        }
+        
+        if self.packing == Packing::Planar && (self.plane_group() == 0 || !self.bits_per_pixel.is_multiple_of(self.plane_group())) {
+            return invalid("plane_group must evenly divide bits_per_pixel");
+        }
+        
+        if !self.tile_bits().is_multiple_of(self.word_bits()) && self.tile_size != (1, 1) {
+            return invalid("each tile must fill a whole number of words");
+        }
+        
+        Ok(())
+    }
+    
+    /// List, in storage order, which bit of which pixel of a tile each bit of
+    /// the tile's stream holds.
+    fn bit_sequence(&self) -> Vec<(usize, u32)> {
+        let (tw, th) = (self.tile_size.0 as usize, self.tile_size.1 as usize);
+        let bpp = self.bits_per_pixel;
+        let mut out = Vec::with_capacity(self.tile_bits());
+        
+        match self.packing {
+            Packing::Packed => for pixel in 0..tw * th {
+                //Within a pixel, bits run in the same direction as pixels do
+                //within a word, so that every pixel reads as a plain number.
+                for bit in 0..bpp {
+                    out.push((pixel, match self.bit_order {
+                        BitOrder::Msb => bpp - 1 - bit,
+                        BitOrder::Lsb => bit
+                    }));
+                }
+            },
+            Packing::Planar => {
+                let group = self.plane_group();
+                for first_plane in (0..bpp).step_by(group as usize) {
+                    for y in 0..th {
+                        for plane in first_plane..first_plane + group {
+                            for x in 0..tw {
+                                out.push((y * tw + x, plane));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        
+        out
+    }
+    
+    /// Convert the position of a bit within the stream to the byte and bit it
+    /// is stored at.
+    fn bit_position(&self, stream_bit: usize) -> (usize, u8) {
+        let word_bits = self.word_bits();
+        let word = stream_bit / word_bits;
+        let bit_in_word = match self.bit_order {
+            BitOrder::Msb => word_bits - 1 - stream_bit % word_bits,
+            BitOrder::Lsb => stream_bit % word_bits
+        };
+        let byte_in_word = match self.endianness {
+            Endianness::Little => bit_in_word / 8,
+            Endianness::Big => self.word_size as usize - 1 - bit_in_word / 8
+        };
+        
+        (word * self.word_size as usize + byte_in_word, (bit_in_word % 8) as u8)
+    }
+    
+    /// The number of bytes needed to store a number of tiles, or of pixels in
+    /// the case of non-tiled layouts.
+    pub fn bytes_for_tiles(&self, tiles: usize) -> usize {
+        let bits = tiles.saturating_mul(self.tile_bits());
+        let word_bits = self.word_bits();
+        
+        bits.div_ceil(word_bits).saturating_mul(self.word_size as usize)
+    }
+    
+    /// Pack tile-ordered indexes into this layout. Indexes are truncated to
+    /// bits_per_pixel bits, and any partial tile at the end is padded with 0.
+    pub fn pack(&self, indexes: &[u8]) -> Vec<u8> {
+        let tile_pixels = (self.tile_size.0 * self.tile_size.1) as usize;
+        let tiles = indexes.len().div_ceil(tile_pixels);
+        let sequence = self.bit_sequence();
+        let mut out = vec![0; self.bytes_for_tiles(tiles)];
+        
+        for tile in 0..tiles {
+            for (i, &(pixel, bit)) in sequence.iter().enumerate() {
+                let index = indexes.get(tile * tile_pixels + pixel).cloned().unwrap_or(0);
+                if index >> bit & 1 == 1 {
+                    let (byte, shift) = self.bit_position(tile * sequence.len() + i);
+                    out[byte] |= 1 << shift;
+                }
+            }
+        }
+        
+        out
+    }
+    
+    /// Unpack as many whole tiles as the data holds into tile-ordered indexes.
+    pub fn unpack(&self, data: &[u8]) -> Vec<u8> {
+        let tile_pixels = (self.tile_size.0 * self.tile_size.1) as usize;
+        let sequence = self.bit_sequence();
+        let tiles = data.len() * 8 / sequence.len();
+        let mut out = vec![0; tiles * tile_pixels];
+        
+        for tile in 0..tiles {
+            for (i, &(pixel, bit)) in sequence.iter().enumerate() {
+                let (byte, shift) = self.bit_position(tile * sequence.len() + i);
+                if byte < data.len() && data[byte] >> shift & 1 == 1 {
+                    out[tile * tile_pixels + pixel] |= 1 << bit;
+                }
+            }
+        }
+        
+        out
+    }
+}
+
+/// Parse every [[layout]] table of a TOML document, validating each.
+pub fn parse_layouts(text: &str) -> Result<Vec<BitLayout>> {
+    let file : LayoutFile = toml::from_str(text).map_err(|e| Error::invalid(format!("Could not parse layouts: {}", e)))?;
+    
+    for layout in file.layout.iter() {
+        layout.validate()?;
+    }
+    
+    Ok(file.layout)
+}
+
+/// Read a TOML file of layout descriptions.
+pub fn read_layout_file(filename: &str) -> Result<Vec<BitLayout>> {
+    let mut text = String::new();
+    OpenOptions::new().read(true).open(filename)?.read_to_string(&mut text)?;
+    
+    parse_layouts(&text)
+}
+
+/// Encoder/decoder for any format described by a BitLayout.
+pub struct LayoutEncoder<'a, F: 'a + ?Sized> {
+    f: &'a mut F,
+    layout: BitLayout
+}
+
+impl<'a, F: 'a + ?Sized> LayoutEncoder<'a, F> {
+    pub fn new(file: &'a mut F, layout: BitLayout) -> LayoutEncoder<'a, F> {
+        LayoutEncoder {
+            f: file,
+            layout
+        }
+    }
+}
+
+impl<'a, F: 'a + ?Sized> IndexedGraphicsProperties for LayoutEncoder<'a, F> {
+    fn tile_size(&self) -> (u32, u32) {
+        self.layout.tile_size
+    }
+    
+    fn attribute_size(&self) -> (u32, u32) {
+        self.layout.attribute_size.unwrap_or(self.layout.tile_size)
+    }
+    
+    fn palette_maxcol(&self) -> u16 {
+        (1u16 << self.layout.bits_per_pixel) - 1
+    }
+    
+    fn bits_per_pixel(&self) -> u32 {
+        self.layout.bits_per_pixel
+    }
+}
+
+impl<'a, F: 'a + ?Sized> IndexedGraphicsEncoder for LayoutEncoder<'a, F> where F: Write {
+    fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, _width: u32, _height: u32) -> io::Result<()> {
+        let indexes : Vec<u8> = data.into_iter().map(|index| index.to_u8().unwrap_or(u8::MAX)).collect();
+        
+        self.f.write_all(&self.layout.pack(&indexes))
+    }
+    
+    fn encode_palette<T: Primitive>(&mut self, palette: Vec<Rgba<T>>) -> io::Result<()> {
+        encode_palette(self.f, palette.into_iter(), false)
+    }
+}
+
+impl<'a, F: 'a + ?Sized> IndexedGraphicsDecoder for LayoutEncoder<'a, F> where F: Read {
+    fn decode_indexes<P: Primitive>(&mut self, size: usize) -> io::Result<Vec<P>> {
+        let tile_pixels = (self.layout.tile_size.0 * self.layout.tile_size.1) as usize;
+        let bytes = self.layout.bytes_for_tiles(size.div_ceil(tile_pixels));
+        
+        let mut data = Vec::new();
+        Read::take(&mut *self.f, bytes as u64).read_to_end(&mut data)?;
+        
+        let mut out = self.layout.unpack(&data);
+        out.truncate(size);
+        Ok(out.into_iter().map(|index| P::from(index).unwrap()).collect())
+    }
+    
+    fn decode_palette(&mut self, count: usize) -> io::Result<Vec<Rgba<u8>>> {
+        decode_palette(self.f, count, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use awsmimg::formats::agb::AGB4Encoder;
+    
+    const LAYOUTS: &str = r#"
+        [[layout]]
+        name = "agb4"
+        bits_per_pixel = 4
+        bit_order = "lsb"
+        
+        [[layout]]
+        name = "nes"
+        description = "NES 2bpp planar tiles"
+        bits_per_pixel = 2
+        packing = "planar"
+        plane_group = 1
+        
+        [[layout]]
+        name = "gb"
+        bits_per_pixel = 2
+        packing = "planar"
+        
+        [[layout]]
+        name = "md"
+        bits_per_pixel = 4
+        word_size = 2
+        endianness = "big"
+    "#;
+    
+    fn layout(name: &str) -> BitLayout {
+        parse_layouts(LAYOUTS).unwrap().into_iter().find(|l| l.name == name).unwrap()
+    }
+    
+    #[test]
+    fn layout_matches_builtin() {
+        let indexes : Vec<u8> = (0..128).map(|i| (i * 7 % 16) as u8).collect();
+        
+        let mut builtin = Vec::new();
+        AGB4Encoder::new(&mut builtin).encode_indexes(indexes.clone(), 16, 8).unwrap();
+        
+        let mut described = Vec::new();
+        LayoutEncoder::new(&mut described, layout("agb4")).encode_indexes(indexes.clone(), 16, 8).unwrap();
+        assert_eq!(builtin, described);
+        
+        let mut src = Cursor::new(described);
+        let decoded : Vec<u8> = LayoutEncoder::new(&mut src, layout("agb4")).decode_indexes(usize::MAX).unwrap();
+        assert_eq!(decoded, indexes);
+    }
+    
+    #[test]
+    fn planar_and_word_layouts() {
+        //A tile whose first row is 0,1,2,3,0,1,2,3 and is otherwise blank.
+        let mut tile = vec![0u8; 64];
+        for x in 0..8 {
+            tile[x] = (x % 4) as u8;
+        }
+        
+        let nes = layout("nes").pack(&tile);
+        assert_eq!(nes.len(), 16);
+        assert_eq!(nes[0], 0b0101_0101);
+        assert_eq!(nes[8], 0b0011_0011);
+        
+        let gb = layout("gb").pack(&tile);
+        assert_eq!(&gb[0..2], &[0b0101_0101, 0b0011_0011]);
+        assert_eq!(layout("gb").unpack(&gb), tile);
+        
+        let md = layout("md").pack(&tile);
+        assert_eq!(&md[0..4], &[0x01, 0x23, 0x01, 0x23]);
+        assert_eq!(layout("md").unpack(&md), tile);
+        
+        assert!(parse_layouts("[[layout]]\nname = \"bad\"\nbits_per_pixel = 3\n").is_err());
+    }
+}
@@ -1,5 +1,6 @@
-//TODO: Can we unpublish agb and provide encoders through boxed access only?
 pub mod agb;
+pub mod agbmap;
+pub mod layout;
 
 /// Supertrait for encoders and decoders of indexed-color image formats.
 pub trait IndexedGraphicsProperties {
@@ -36,8 +37,18 @@ pub trait IndexedGraphicsProperties {
     /// This is the largest index that the format can represent. It does not
     /// imply a limit on the size of palette Vec<u8>s passed to encode_palette.
     fn palette_maxcol(&self) -> u16;
+    
+    /// Retrieves the number of bits each pixel occupies in this image format.
+    /// 
+    /// By default this is the number of bits needed to represent every index
+    /// up to palette_maxcol. Formats which pad or compress their pixels should
+    /// override it.
+    fn bits_per_pixel(&self) -> u32 {
+        (self.palette_maxcol() as u32 + 1).next_power_of_two().trailing_zeros()
+    }
 }
 
+#[derive(Copy, Clone)]
 pub enum IndexedFormat {
     AGB4,       //4 bits per pixel, packed, arranged row-major in 8x8 tiles
     AGB8Tiled,  //8 bits per pixel, packed, arranged row-major in 8x8 tiles
@@ -68,4 +79,42 @@ pub fn interpret_direct_format_name(fmt_given: &str) -> Option<DirectFormat> {
         "ntr16" => Some(DirectFormat::NTR16),
         _ => None
     }
-}
\ No newline at end of file
+}
+
+/// Supertrait for encoders and decoders of tilemap formats.
+pub trait TilemapProperties {
+    /// Retrieves the largest tile number a map entry can refer to.
+    fn max_tile(&self) -> usize;
+    
+    /// Retrieves whether map entries can mirror their tiles.
+    fn allows_flips(&self) -> bool;
+    
+    /// Retrieves the largest palette bank a map entry can select.
+    /// 
+    /// Map formats without palette banks should return 0.
+    fn max_palette_bank(&self) -> u8;
+    
+    /// Determines if a map of the given size, in tiles, can be represented in
+    /// this map format.
+    fn valid_map_size(&self, width: u32, height: u32) -> bool;
+    
+    /// Determines if this map format can display tiles of the given tile size
+    /// and maximum color index.
+    fn supports_tiles(&self, tsize: (u32, u32), maxcol: u16) -> bool;
+}
+
+#[derive(Copy, Clone)]
+pub enum MapFormat {
+    AGBText,  //16 bits per entry, 10-bit tile, flips, 4-bit palette bank, in 32x32 screenblocks
+    AGBAffine //8 bits per entry, 8-bit tile, no flips, row-major, 8bpp tiles only
+}
+
+pub fn interpret_map_format_name(fmt_given: &str) -> Option<MapFormat> {
+    let fmt = fmt_given.to_ascii_lowercase();
+    
+    match fmt.as_ref() {
+        "agbtext" => Some(MapFormat::AGBText),
+        "agbaffine" => Some(MapFormat::AGBAffine),
+        _ => None
+    }
+}
@@ -0,0 +1,151 @@
+use image::{GenericImage, ImageBuffer, Pixel, Primitive, Rgba};
+
+use awsmimg::error::{Error, Result};
+use awsmimg::conversion::rgba8_from_pixel;
+
+/// What to label each tile of a grid overlay with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GridLabel {
+    None,
+    TileNumbers,
+    Offsets
+}
+
+pub fn interpret_grid_label_name(name: &str) -> Option<GridLabel> {
+    let name = name.to_ascii_lowercase();
+    
+    match name.as_ref() {
+        "none" => Some(GridLabel::None),
+        "tiles" => Some(GridLabel::TileNumbers),
+        "offsets" => Some(GridLabel::Offsets),
+        _ => None
+    }
+}
+
+/// Settings for drawing a tile grid over a decoded image.
+pub struct GridOptions {
+    /// The size of a tile, in unscaled pixels.
+    pub tsize: (u32, u32),
+    
+    /// How many times larger than the original the annotated image is.
+    pub scale: u32,
+    
+    pub labels: GridLabel,
+    
+    /// Where the first tile was read from, for offset labels.
+    pub base_offset: u64,
+    
+    /// The size of a single encoded tile, in bytes, for offset labels.
+    pub tile_bytes: u64
+}
+
+const GRID_COLOR: [u8; 4] = [255, 0, 255, 255];
+const LABEL_COLOR: [u8; 4] = [255, 255, 255, 255];
+const LABEL_BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+
+/// A 3x5 pixel font of hexadecimal digits. Each row is three bits, with the
+/// leftmost pixel in the highest bit.
+const HEX_FONT: [[u8; 5]; 16] = [[7, 5, 5, 5, 7], [2, 6, 2, 2, 7], [7, 1, 7, 4, 7], [7, 1, 7, 1, 7],
+                                 [5, 5, 7, 1, 1], [7, 4, 7, 1, 7], [7, 4, 7, 5, 7], [7, 1, 1, 1, 1],
+                                 [7, 5, 7, 5, 7], [7, 5, 7, 1, 7], [2, 5, 7, 5, 5], [6, 5, 6, 5, 6],
+                                 [3, 4, 4, 4, 3], [6, 5, 5, 5, 6], [7, 4, 7, 4, 7], [7, 4, 7, 4, 4]];
+
+/// Determine the label of a tile, given its position in tile order.
+pub fn tile_label(options: &GridOptions, tile: u64) -> Option<String> {
+    match options.labels {
+        GridLabel::None => None,
+        GridLabel::TileNumbers => Some(format!("{:X}", tile)),
+        GridLabel::Offsets => Some(format!("{:X}", options.base_offset + tile * options.tile_bytes))
+    }
+}
+
+/// Draw a hex string in the tiny font with its top-left corner at the given
+/// position, clipped to the given bounds.
+fn draw_label(out: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, text: &str, x: u32, y: u32, bounds: (u32, u32)) {
+    let width = text.len() as u32 * 4 + 1;
+    
+    for py in y..(y + 7).min(bounds.1) {
+        for px in x..(x + width).min(bounds.0) {
+            out.put_pixel(px, py, Rgba(LABEL_BACKGROUND));
+        }
+    }
+    
+    for (i, digit) in text.chars().filter_map(|c| c.to_digit(16)).enumerate() {
+        for (row, bits) in HEX_FONT[digit as usize].iter().enumerate() {
+            for col in 0..3 {
+                let (px, py) = (x + 1 + i as u32 * 4 + col, y + 1 + row as u32);
+                
+                if bits & (4 >> col) != 0 && px < bounds.0 && py < bounds.1 {
+                    out.put_pixel(px, py, Rgba(LABEL_COLOR));
+                }
+            }
+        }
+    }
+}
+
+/// Produce a scaled-up copy of a decoded image with the boundaries of each
+/// tile drawn over it, and each tile optionally labeled with its number or
+/// the offset it was read from.
+/// 
+/// Labels that do not fit within their tile are clipped. The annotated image
+/// is meant only for reference: the grid and labels would be encoded as data
+/// if it were converted back.
+pub fn annotate_grid<I, P, S>(image: &I, options: &GridOptions) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    let (tw, th) = options.tsize;
+    let scale = options.scale;
+    
+    if tw < 2 || th < 2 || scale == 0 {
+        return Err(Error::invalid("Tile grids require a tiled format and a nonzero scale"));
+    }
+    
+    let (iw, ih) = image.dimensions();
+    let (ow, oh) = (iw * scale, ih * scale);
+    let mut out = ImageBuffer::from_fn(ow, oh, |x, y| rgba8_from_pixel(image.get_pixel(x / scale, y / scale)));
+    let (cw, ch) = (tw * scale, th * scale);
+    
+    for y in 0..oh {
+        for x in 0..ow {
+            if x % cw == 0 || y % ch == 0 || x == ow - 1 || y == oh - 1 {
+                out.put_pixel(x, y, Rgba(GRID_COLOR));
+            }
+        }
+    }
+    
+    let tiles_wide = iw / tw;
+    
+    for ty in 0..ih / th {
+        for tx in 0..tiles_wide {
+            if let Some(text) = tile_label(options, (ty * tiles_wide + tx) as u64) {
+                let (x, y) = (tx * cw + 1, ty * ch + 1);
+                draw_label(&mut out, &text, x, y, ((tx + 1) * cw, (ty + 1) * ch));
+            }
+        }
+    }
+    
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, LumaA, Rgba};
+    use awsmimg::grid::{GridOptions, GridLabel, annotate_grid, tile_label};
+    
+    #[test]
+    fn grid_overlay() {
+        let img : ImageBuffer<LumaA<u8>, Vec<u8>> = ImageBuffer::from_pixel(16, 8, LumaA([128u8, 255]));
+        let options = GridOptions { tsize: (8, 8), scale: 2, labels: GridLabel::TileNumbers, base_offset: 0x100, tile_bytes: 32 };
+        
+        let out = annotate_grid(&img, &options).unwrap();
+        
+        assert_eq!(out.dimensions(), (32, 16));
+        assert_eq!(*out.get_pixel(16, 10), Rgba([255u8, 0, 255, 255]));
+        assert_eq!(*out.get_pixel(24, 12), Rgba([128u8, 128, 128, 255]));
+        
+        //The "1" glyph's top row is lit in its middle column only.
+        assert_eq!(*out.get_pixel(18, 2), Rgba([0u8, 0, 0, 255]));
+        assert_eq!(*out.get_pixel(19, 2), Rgba([255u8, 255, 255, 255]));
+        
+        let offsets = GridOptions { labels: GridLabel::Offsets, ..options };
+        assert_eq!(tile_label(&offsets, 3), Some("160".to_string()));
+    }
+}
@@ -1,6 +1,25 @@
+pub mod error;
 pub mod conversion;
 pub mod encoder;
 pub mod decoder;
 pub mod tiles;
 pub mod formats;
-pub mod compression;
\ No newline at end of file
+pub mod compression;
+pub mod quantize;
+pub mod subpalettes;
+pub mod dither;
+pub mod palette;
+pub mod render;
+pub mod vram;
+pub mod sprites;
+pub mod oam;
+pub mod bitmap;
+pub mod grid;
+pub mod registry;
+pub mod validate;
+pub mod verify;
+pub mod patch;
+pub mod checksum;
+pub mod address;
+
+pub use self::error::{Error, Result};
@@ -0,0 +1,268 @@
+use std::io;
+use std::cmp::Reverse;
+use std::io::Cursor;
+use image::{ImageBuffer, Rgba};
+
+use awsmimg::error::Result;
+use awsmimg::decoder::IndexedGraphicsDecoder;
+use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder};
+use awsmimg::render::render_scene;
+use awsmimg::sprites::{ObjMapping, OBJ_SIZES};
+use awsmimg::tiles::MapEntry;
+
+/// Number of sprites described by OAM.
+pub const OAM_ENTRIES: usize = 128;
+
+/// Size of the AGB screen, in pixels.
+pub const SCREEN_SIZE: (u32, u32) = (240, 160);
+
+/// Size of a single tile slot of sprite memory. 256-color tiles occupy two.
+const OBJ_TILE_UNIT: usize = 32;
+
+/// The attributes of a single sprite, as stored in OAM.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ObjAttributes {
+    pub x: i32,
+    pub y: i32,
+    pub affine: bool,
+    
+    /// For affine sprites, the double-size flag. Otherwise, the flag which
+    /// hides the sprite.
+    pub double_or_disable: bool,
+    
+    pub mode: u8,
+    pub colors256: bool,
+    pub shape: u8,
+    pub size: u8,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub tile: usize,
+    pub priority: u8,
+    pub palette: u8
+}
+
+impl ObjAttributes {
+    pub fn from_attributes(attr0: u16, attr1: u16, attr2: u16) -> ObjAttributes {
+        let affine = attr0 & 0x100 != 0;
+        
+        ObjAttributes {
+            //Y coordinates wrap around the bottom of the 256-line space; X
+            //coordinates are 9-bit signed.
+            y: (attr0 & 0xFF) as i32,
+            x: ((attr1 & 0x1FF) as i32 ^ 0x100) - 0x100,
+            affine,
+            double_or_disable: attr0 & 0x200 != 0,
+            mode: ((attr0 >> 10) & 0x3) as u8,
+            colors256: attr0 & 0x2000 != 0,
+            shape: (attr0 >> 14) as u8,
+            size: (attr1 >> 14) as u8,
+            hflip: !affine && attr1 & 0x1000 != 0,
+            vflip: !affine && attr1 & 0x2000 != 0,
+            tile: (attr2 & 0x3FF) as usize,
+            priority: ((attr2 >> 10) & 0x3) as u8,
+            palette: (attr2 >> 12) as u8
+        }
+    }
+    
+    /// The size of the sprite, in pixels, or None if its shape is invalid.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        OBJ_SIZES.get(self.shape as usize).map(|sizes| sizes[self.size as usize])
+    }
+    
+    /// Whether the hardware would draw this sprite at all.
+    /// 
+    /// OBJ window sprites (mode 2) only mask other layers and are never drawn
+    /// themselves, and mode 3 is prohibited.
+    pub fn visible(&self) -> bool {
+        self.dimensions().is_some() && self.mode < 2 && (self.affine || !self.double_or_disable)
+    }
+    
+    /// Where the sprite's top-left corner is drawn on screen.
+    /// 
+    /// Affine sprites are drawn untransformed; double-size affine sprites are
+    /// centered within their doubled area, as an identity transform would.
+    pub fn position(&self) -> (i32, i32) {
+        let (w, h) = self.dimensions().unwrap_or((0, 0));
+        let (mut x, mut y) = (self.x, self.y);
+        
+        if y + h as i32 > 256 {
+            y -= 256;
+        }
+        
+        if self.affine && self.double_or_disable {
+            x += w as i32 / 2;
+            y += h as i32 / 2;
+        }
+        
+        (x, y)
+    }
+}
+
+/// Parse every sprite out of an OAM dump.
+pub fn parse_oam(data: &[u8]) -> Result<Vec<ObjAttributes>> {
+    if data.len() < OAM_ENTRIES * 8 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "OAM dump is shorter than 1024 bytes").into());
+    }
+    
+    Ok(data.chunks(8).take(OAM_ENTRIES).map(|e| {
+        let attr = |i: usize| e[i] as u16 | (e[i + 1] as u16) << 8;
+        ObjAttributes::from_attributes(attr(0), attr(2), attr(4))
+    }).collect())
+}
+
+/// Determine the sprite mapping mode selected by a DISPCNT register value.
+pub fn obj_mapping_from_dispcnt(dispcnt: u16) -> ObjMapping {
+    match dispcnt & 0x40 {
+        0 => ObjMapping::TwoDimensional,
+        _ => ObjMapping::OneDimensional
+    }
+}
+
+/// Decode a single tile of sprite memory, given its first tile slot.
+/// 
+/// Tiles beyond the end of the dump decode as blank.
+fn decode_obj_tile(obj_vram: &[u8], slot: usize, colors256: bool) -> Result<Vec<u8>> {
+    let start = slot * OBJ_TILE_UNIT;
+    
+    match colors256 {
+        false => match obj_vram.get(start..start + OBJ_TILE_UNIT) {
+            Some(data) => Ok(AGB4Encoder::new(&mut Cursor::new(data)).decode_indexes(64)?),
+            None => Ok(vec![0; 64])
+        },
+        true => match obj_vram.get(start..start + OBJ_TILE_UNIT * 2) {
+            Some(data) => Ok(AGB8Encoder::new_tiled(&mut Cursor::new(data)).decode_indexes(64)?),
+            None => Ok(vec![0; 64])
+        }
+    }
+}
+
+/// Render a single sprite from sprite memory and the sprite palette.
+/// 
+/// The sprite's flips and palette bank are honored, and index 0 is
+/// transparent. Affine transformations are not applied.
+pub fn render_obj(obj_vram: &[u8], obj: &ObjAttributes, palette: &[Rgba<u8>], mapping: ObjMapping) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let (w, h) = match obj.dimensions() {
+        Some(dims) => dims,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Sprite has an invalid shape").into())
+    };
+    let (fw, fh) = (w / 8, h / 8);
+    let step = if obj.colors256 { 2 } else { 1 };
+    let slots = obj_vram.len() / OBJ_TILE_UNIT;
+    
+    let mut tiles = Vec::with_capacity((fw * fh) as usize);
+    let mut map = Vec::with_capacity((fw * fh) as usize);
+    
+    for j in 0..fh {
+        for i in 0..fw {
+            let offset = match mapping {
+                ObjMapping::OneDimensional => (j * fw + i) as usize * step,
+                ObjMapping::TwoDimensional => (j * 32) as usize + i as usize * step
+            };
+            
+            tiles.push(decode_obj_tile(obj_vram, (obj.tile + offset) % slots.max(1), obj.colors256)?);
+        }
+    }
+    
+    //Flipping a sprite mirrors both its tiles and their arrangement.
+    for j in 0..fh {
+        for i in 0..fw {
+            let si = if obj.hflip { fw - 1 - i } else { i };
+            let sj = if obj.vflip { fh - 1 - j } else { j };
+            
+            map.push(MapEntry { tile: (sj * fw + si) as usize, hflip: obj.hflip, vflip: obj.vflip, palette: obj.palette });
+        }
+    }
+    
+    let bank_size = if obj.colors256 { 0 } else { 16 };
+    
+    Ok(render_scene(&tiles, &map, (fw, fh), (8, 8), bank_size, palette))
+}
+
+/// Composite every visible sprite onto a transparent screen-sized canvas.
+/// 
+/// Sprites with a lower priority value are drawn in front; among sprites of
+/// equal priority, those earlier in OAM are drawn in front.
+pub fn render_oam(obj_vram: &[u8], oam: &[ObjAttributes], palette: &[Rgba<u8>], mapping: ObjMapping) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let (sw, sh) = SCREEN_SIZE;
+    let mut canvas = ImageBuffer::from_pixel(sw, sh, Rgba([0u8, 0, 0, 0]));
+    
+    let mut order : Vec<&ObjAttributes> = oam.iter().filter(|o| o.visible()).collect();
+    order.reverse();
+    order.sort_by_key(|o| Reverse(o.priority));
+    
+    for obj in order {
+        let sprite = render_obj(obj_vram, obj, palette, mapping)?;
+        let (ox, oy) = obj.position();
+        
+        for (x, y, pixel) in sprite.enumerate_pixels() {
+            let (cx, cy) = (ox + x as i32, oy + y as i32);
+            
+            if pixel[3] != 0 && cx >= 0 && cy >= 0 && (cx as u32) < sw && (cy as u32) < sh {
+                canvas.put_pixel(cx as u32, cy as u32, *pixel);
+            }
+        }
+    }
+    
+    Ok(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use awsmimg::oam::{ObjAttributes, parse_oam, render_obj, render_oam, obj_mapping_from_dispcnt};
+    use awsmimg::sprites::ObjMapping;
+    
+    #[test]
+    fn oam_attributes() {
+        let mut oam = vec![0u8; 1024];
+        //16x8 sprite at (-8, 150), hflip, tile 5, priority 1, palette 3.
+        oam[0..6].copy_from_slice(&[150, 0x40, 0xF8, 0x11, 0x05, 0x34]);
+        
+        let objs = parse_oam(&oam).unwrap();
+        
+        assert_eq!(objs.len(), 128);
+        assert_eq!(objs[0].x, -8);
+        assert_eq!(objs[0].position(), (-8, 150));
+        assert_eq!(objs[0].dimensions(), Some((16, 8)));
+        assert!(objs[0].hflip && !objs[0].vflip);
+        assert_eq!((objs[0].tile, objs[0].priority, objs[0].palette), (5, 1, 3));
+        assert!(objs[0].visible());
+        assert!(parse_oam(&oam[..8]).is_err());
+        
+        //Semi-transparent sprites are drawn; OBJ window and prohibited ones are not.
+        assert!(ObjAttributes::from_attributes(0x0400, 0, 0).visible());
+        assert!(!ObjAttributes::from_attributes(0x0800, 0, 0).visible());
+        assert!(!ObjAttributes::from_attributes(0x0C00, 0, 0).visible());
+        assert_eq!(obj_mapping_from_dispcnt(0x1040), ObjMapping::OneDimensional);
+    }
+    
+    #[test]
+    fn obj_rendering() {
+        let mut vram = vec![0u8; 0x8000];
+        //Tile 2 is solid color 1, tile 3 is blank.
+        for b in vram[64..96].iter_mut() {
+            *b = 0x11;
+        }
+        
+        let mut palette = vec![Rgba([0u8, 0, 0, 255]); 256];
+        palette[0x21] = Rgba([0, 255, 0, 255]);
+        palette[0x31] = Rgba([255, 0, 0, 255]);
+        
+        //16x8, tile 2, palette 2, hflipped: the solid tile moves to the right.
+        let obj = ObjAttributes::from_attributes(0x4000, 0x1000, 0x2002);
+        let sprite = render_obj(&vram, &obj, &palette, ObjMapping::OneDimensional).unwrap();
+        
+        assert_eq!(sprite.dimensions(), (16, 8));
+        assert_eq!(*sprite.get_pixel(0, 0), Rgba([0u8, 0, 0, 0]));
+        assert_eq!(*sprite.get_pixel(15, 7), Rgba([0u8, 255, 0, 255]));
+        
+        //The same sprite in palette 3 at a lower priority is drawn behind.
+        let behind = ObjAttributes::from_attributes(0x4000, 0x1000, 0x3402);
+        let hidden = ObjAttributes::from_attributes(0x0200, 0, 0x0002);
+        let canvas = render_oam(&vram, &[behind, obj, hidden], &palette, ObjMapping::OneDimensional).unwrap();
+        
+        assert_eq!(canvas.dimensions(), (240, 160));
+        assert_eq!(*canvas.get_pixel(8, 0), Rgba([0u8, 255, 0, 255]));
+        assert_eq!(*canvas.get_pixel(0, 0), Rgba([0u8, 0, 0, 0]));
+    }
+}
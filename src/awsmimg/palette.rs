@@ -0,0 +1,365 @@
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::fs::OpenOptions;
+use std::path::Path;
+use image::Rgba;
+
+use awsmimg::error::{Error, Result};
+use awsmimg::formats::agb::{encode_palette, decode_palette};
+
+/// File formats that palettes can be imported from or exported to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PaletteFormat {
+    JascPal, //Paint Shop Pro text palette, also used by Usenti and others
+    Gimp,    //GIMP .gpl text palette
+    Act,     //Adobe Color Table: 256 RGB triplets plus an optional footer
+    Raw      //Hardware palette data, as written by encode_palette
+}
+
+pub fn interpret_palette_format_name(fmt_given: &str) -> Option<PaletteFormat> {
+    let fmt = fmt_given.to_ascii_lowercase();
+    
+    match fmt.as_ref() {
+        "jasc" | "pal" => Some(PaletteFormat::JascPal),
+        "gimp" | "gpl" => Some(PaletteFormat::Gimp),
+        "act" => Some(PaletteFormat::Act),
+        "raw" | "bgr555" => Some(PaletteFormat::Raw),
+        _ => None
+    }
+}
+
+/// Interpret an optional palette format name, such as one given on the
+/// command line, where an empty name means the format should be guessed.
+pub fn palette_format_option(name: &str) -> Result<Option<PaletteFormat>> {
+    match (name.is_empty(), interpret_palette_format_name(name)) {
+        (true, _) => Ok(None),
+        (false, Some(f)) => Ok(Some(f)),
+        (false, None) => Err(Error::UnknownFormat(name.to_string()))
+    }
+}
+
+fn file_extension(filename: &str) -> String {
+    Path::new(filename).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase()
+}
+
+/// Guess which format a palette file is stored in, by contents if possible
+/// and by file extension otherwise.
+/// 
+/// Only the start of the file needs to be given as data, along with the
+/// length of the whole file. Text palettes are recognized by their headers.
+/// Files named .act that are the size of an Adobe Color Table are treated as
+/// one. Anything else is assumed to be raw hardware palette data, such as a
+/// palette RAM dump or a ROM.
+pub fn guess_palette_format(filename: &str, data: &[u8], length: u64) -> PaletteFormat {
+    if data.starts_with(b"JASC-PAL") {
+        PaletteFormat::JascPal
+    } else if data.starts_with(b"GIMP Palette") {
+        PaletteFormat::Gimp
+    } else if file_extension(filename) == "act" && (length == 768 || length == 772) {
+        PaletteFormat::Act
+    } else {
+        PaletteFormat::Raw
+    }
+}
+
+/// Choose the format a palette should be written in, based on the extension
+/// of the file it is being written to.
+/// 
+/// .pal is taken to mean a JASC-PAL file, as that is what most paint programs
+/// expect of it. Unrecognized extensions yield raw hardware palette data.
+pub fn palette_format_for_filename(filename: &str) -> PaletteFormat {
+    match file_extension(filename).as_ref() {
+        "pal" => PaletteFormat::JascPal,
+        "gpl" => PaletteFormat::Gimp,
+        "act" => PaletteFormat::Act,
+        _ => PaletteFormat::Raw
+    }
+}
+
+fn invalid_palette(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse a line holding a whitespace-separated RGB triplet.
+fn parse_rgb_line(line: &str, lineno: usize) -> io::Result<Rgba<u8>> {
+    let mut channels = [0u8; 3];
+    let mut fields = line.split_whitespace();
+    
+    for channel in channels.iter_mut() {
+        *channel = match fields.next().map(|f| f.parse::<u8>()) {
+            Some(Ok(v)) => v,
+            _ => return Err(invalid_palette(format!("Line {} of palette file is not a valid RGB color", lineno + 1)))
+        };
+    }
+    
+    Ok(Rgba([channels[0], channels[1], channels[2], 255]))
+}
+
+fn read_jasc_palette(text: &str) -> io::Result<Vec<Rgba<u8>>> {
+    let mut lines = text.lines().enumerate();
+    
+    match (lines.next(), lines.next()) {
+        (Some((_, "JASC-PAL")), Some((_, "0100"))) => {},
+        _ => return Err(invalid_palette("Missing or unsupported JASC-PAL header".to_string()))
+    }
+    
+    let count = match lines.next().map(|(_, l)| l.trim().parse::<usize>()) {
+        Some(Ok(c)) => c,
+        _ => return Err(invalid_palette("JASC-PAL color count is missing".to_string()))
+    };
+    
+    let mut out = Vec::with_capacity(count);
+    
+    for (lineno, line) in lines.take(count) {
+        out.push(parse_rgb_line(line, lineno)?);
+    }
+    
+    if out.len() < count {
+        return Err(invalid_palette(format!("JASC-PAL file declares {} colors but holds only {}", count, out.len())));
+    }
+    
+    Ok(out)
+}
+
+fn read_gimp_palette(text: &str) -> io::Result<Vec<Rgba<u8>>> {
+    let mut out = Vec::new();
+    
+    for (lineno, line) in text.lines().enumerate().skip(1) {
+        let trimmed = line.trim();
+        
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("Name:") || trimmed.starts_with("Columns:") {
+            continue;
+        }
+        
+        out.push(parse_rgb_line(trimmed, lineno)?);
+    }
+    
+    Ok(out)
+}
+
+fn read_act_palette(data: &[u8]) -> io::Result<Vec<Rgba<u8>>> {
+    if data.len() < 768 {
+        return Err(invalid_palette("Adobe Color Table is shorter than 768 bytes".to_string()));
+    }
+    
+    let mut out : Vec<Rgba<u8>> = data[..768].chunks(3).map(|c| Rgba([c[0], c[1], c[2], 255])).collect();
+    
+    if data.len() >= 772 {
+        let count = (data[768] as usize) << 8 | data[769] as usize;
+        let transparent = (data[770] as usize) << 8 | data[771] as usize;
+        
+        if count > 0 && count <= 256 {
+            out.truncate(count);
+        }
+        
+        if let Some(c) = out.get_mut(transparent) {
+            c[3] = 0;
+        }
+    }
+    
+    Ok(out)
+}
+
+/// Parse a palette file's contents.
+/// 
+/// Raw hardware palettes are decoded in full; an odd trailing byte is
+/// ignored.
+pub fn read_palette(data: &[u8], format: PaletteFormat) -> Result<Vec<Rgba<u8>>> {
+    Ok(match format {
+        PaletteFormat::JascPal => read_jasc_palette(&String::from_utf8_lossy(data))?,
+        PaletteFormat::Gimp => read_gimp_palette(&String::from_utf8_lossy(data))?,
+        PaletteFormat::Act => read_act_palette(data)?,
+        PaletteFormat::Raw => decode_palette(&mut io::Cursor::new(data), data.len() / 2, false)?
+    })
+}
+
+/// Write a palette in the given file format.
+/// 
+/// Adobe Color Tables are always padded to 256 colors, with the true number
+/// of colors recorded in the footer. If the first color is transparent, it is
+/// recorded as the table's transparent color.
+pub fn write_palette<W: Write>(w: &mut W, format: PaletteFormat, palette: &[Rgba<u8>]) -> Result<()> {
+    match format {
+        PaletteFormat::JascPal => {
+            write!(w, "JASC-PAL\r\n0100\r\n{}\r\n", palette.len())?;
+            for c in palette.iter() {
+                write!(w, "{} {} {}\r\n", c[0], c[1], c[2])?;
+            }
+        },
+        PaletteFormat::Gimp => {
+            writeln!(w, "GIMP Palette")?;
+            writeln!(w, "Name: awsmimg")?;
+            writeln!(w, "Columns: 16")?;
+            writeln!(w, "#")?;
+            for (i, c) in palette.iter().enumerate() {
+                writeln!(w, "{:3} {:3} {:3}\tIndex {}", c[0], c[1], c[2], i)?;
+            }
+        },
+        PaletteFormat::Act => {
+            if palette.len() > 256 {
+                return Err(Error::invalid("Adobe Color Tables cannot hold more than 256 colors"));
+            }
+            
+            let mut table = vec![0u8; 772];
+            for (i, c) in palette.iter().enumerate() {
+                table[i * 3..i * 3 + 3].copy_from_slice(&[c[0], c[1], c[2]]);
+            }
+            
+            let transparent : u16 = match palette.first() {
+                Some(c) if c[3] == 0 => 0,
+                _ => 0xFFFF
+            };
+            
+            table[768] = (palette.len() >> 8) as u8;
+            table[769] = (palette.len() & 0xFF) as u8;
+            table[770] = (transparent >> 8) as u8;
+            table[771] = (transparent & 0xFF) as u8;
+            w.write_all(&table)?;
+        },
+        PaletteFormat::Raw => encode_palette(w, palette.iter().cloned(), false)?
+    }
+    
+    Ok(())
+}
+
+/// Load a palette from a file in any supported format.
+/// 
+/// If format is None, the format is guessed from the file. For raw hardware
+/// palettes, offset selects where within the file the palette begins, which
+/// allows reading palettes straight out of a ROM or memory dump; it must be
+/// zero for other formats. If colors is nonzero, exactly that many colors are
+/// read. Otherwise, raw palettes are read up to 256 colors or the end of the
+/// file, and other formats are read in full.
+pub fn read_palette_file(filename: &str, format: Option<PaletteFormat>, offset: u64, colors: usize) -> Result<Vec<Rgba<u8>>> {
+    read_palette_file_from(filename, format, offset, 0, colors)
+}
+
+/// Load a palette from a file like read_palette_file, starting first_color
+/// colors into it.
+/// 
+/// Raw palettes begin that many colors past offset, while other formats skip
+/// that many of their entries. This selects the same colors either way, such
+/// as the sprite half of a full palette memory dump.
+pub fn read_palette_file_from(filename: &str, format: Option<PaletteFormat>, offset: u64, first_color: usize, colors: usize) -> Result<Vec<Rgba<u8>>> {
+    let mut file = OpenOptions::new().read(true).open(filename)?;
+    let length = file.seek(io::SeekFrom::End(0))?;
+    file.seek(io::SeekFrom::Start(0))?;
+    
+    let mut header = Vec::with_capacity(16);
+    (&mut file).take(16).read_to_end(&mut header)?;
+    
+    let format = format.unwrap_or_else(|| guess_palette_format(filename, &header, length));
+    
+    if format == PaletteFormat::Raw {
+        let offset = offset + first_color as u64 * 2;
+        if offset > length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proposed palette offset exceeds length of palette file.").into());
+        }
+        
+        file.seek(io::SeekFrom::Start(offset))?;
+        
+        let count = match colors {
+            0 => ((length - offset) / 2).min(256) as usize,
+            c => c
+        };
+        
+        return Ok(decode_palette(&mut file, count, false)?);
+    }
+    
+    if offset != 0 {
+        return Err(Error::invalid("Palette offsets are only meaningful for raw hardware palettes."));
+    }
+    
+    let mut data = Vec::with_capacity(length as usize);
+    file.seek(io::SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+    
+    let mut palette = read_palette(&data, format)?;
+    
+    if first_color > 0 {
+        if palette.len() <= first_color {
+            return Err(invalid_palette(format!("Palette file holds only {} colors", palette.len())).into());
+        }
+        palette.drain(..first_color);
+    }
+    
+    if colors > 0 {
+        if palette.len() < colors {
+            return Err(invalid_palette(format!("Palette file holds only {} colors", palette.len())).into());
+        }
+        palette.truncate(colors);
+    }
+    
+    Ok(palette)
+}
+
+/// Save a palette to a file, in the given format or, if format is None, in
+/// the format implied by the file's extension.
+pub fn write_palette_file(filename: &str, format: Option<PaletteFormat>, palette: &[Rgba<u8>]) -> Result<()> {
+    let format = format.unwrap_or_else(|| palette_format_for_filename(filename));
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(filename)?;
+    
+    write_palette(&mut file, format, palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use awsmimg::palette::{PaletteFormat, read_palette, write_palette, guess_palette_format, read_palette_file, read_palette_file_from};
+    
+    fn sample_palette() -> Vec<Rgba<u8>> {
+        vec![Rgba([0u8, 0, 0, 255]), Rgba([255u8, 255, 255, 255]), Rgba([0x84u8, 0x10, 0xFF, 255])]
+    }
+    
+    #[test]
+    fn palette_text_roundtrip() {
+        for fmt in [PaletteFormat::JascPal, PaletteFormat::Gimp, PaletteFormat::Act, PaletteFormat::Raw].iter() {
+            let mut out = Vec::new();
+            write_palette(&mut out, *fmt, &sample_palette()).unwrap();
+            
+            assert_eq!(guess_palette_format("test.act", &out, out.len() as u64), *fmt);
+            assert_eq!(read_palette(&out, *fmt).unwrap(), sample_palette());
+        }
+    }
+    
+    #[test]
+    fn palette_file_guess() {
+        let path = env::temp_dir().join(format!("awsmimg-palette-guess-{}.act", process::id()));
+        let filename = path.to_str().unwrap();
+        let mut out = Vec::new();
+        write_palette(&mut out, PaletteFormat::Act, &sample_palette()).unwrap();
+        fs::write(&path, &out).unwrap();
+        
+        let palette = read_palette_file(filename, None, 0, 0);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(palette.unwrap(), sample_palette());
+        
+        //Skipping colors selects the same entries from text and raw files.
+        let full : Vec<Rgba<u8>> = (0..260).map(|i| Rgba([(i % 32 * 8) as u8, (i / 32 * 8) as u8, 0, 255])).collect();
+        for fmt in [PaletteFormat::JascPal, PaletteFormat::Raw].iter() {
+            let path = env::temp_dir().join(format!("awsmimg-palette-skip-{}.pal", process::id()));
+            let filename = path.to_str().unwrap();
+            let mut out = Vec::new();
+            write_palette(&mut out, *fmt, &full).unwrap();
+            fs::write(&path, &out).unwrap();
+            
+            let whole = read_palette_file_from(filename, Some(*fmt), 0, 0, 260);
+            let palette = read_palette_file_from(filename, Some(*fmt), 0, 256, 0);
+            let short = read_palette_file_from(filename, Some(*fmt), 0, 260, 0);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(palette.unwrap(), whole.unwrap()[256..].to_vec());
+            assert!(*fmt == PaletteFormat::Raw || short.is_err());
+        }
+    }
+    
+    #[test]
+    fn palette_jasc_parse() {
+        let text = b"JASC-PAL\r\n0100\r\n2\r\n255 0 0\r\n0 128 255\r\n";
+        
+        assert_eq!(read_palette(text, PaletteFormat::JascPal).unwrap(), vec![Rgba([255u8, 0, 0, 255]), Rgba([0u8, 128, 255, 255])]);
+        assert!(read_palette(b"JASC-PAL\r\n0100\r\n3\r\n255 0 0\r\n", PaletteFormat::JascPal).is_err());
+    }
+}
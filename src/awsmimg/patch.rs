@@ -0,0 +1,508 @@
+use std::fs;
+use std::path::Path;
+
+use awsmimg::error::{Error, Result};
+
+/// Formats that the changes made to a file can be distributed in, so that the
+/// changed file itself never has to be.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PatchFormat {
+    Ips, //International Patching System: absolute records, files up to 16MB
+    Bps  //beat patch: relative copy commands with CRC32 checks of everything
+}
+
+pub fn interpret_patch_format_name(fmt_given: &str) -> Option<PatchFormat> {
+    let fmt = fmt_given.to_ascii_lowercase();
+    
+    match fmt.as_ref() {
+        "ips" => Some(PatchFormat::Ips),
+        "bps" => Some(PatchFormat::Bps),
+        _ => None
+    }
+}
+
+/// Choose a patch format based on the extension of the patch file, if it has
+/// a recognizable one.
+pub fn patch_format_for_filename(filename: &str) -> Option<PatchFormat> {
+    Path::new(filename).extension().and_then(|e| e.to_str()).and_then(interpret_patch_format_name)
+}
+
+/// Interpret a patch format name given on the command line for the patch
+/// file filename, where an empty name means the format is chosen by the
+/// file's extension.
+pub fn patch_format_option(name: &str, filename: &str) -> Result<PatchFormat> {
+    match (name.is_empty(), interpret_patch_format_name(name), patch_format_for_filename(filename)) {
+        (false, Some(format), _) | (true, _, Some(format)) => Ok(format),
+        (false, None, _) => Err(Error::UnknownFormat(name.to_string())),
+        (true, _, None) => Err(Error::invalid(format!("Cannot tell the format of {} from its name; use --patch-format.", filename)))
+    }
+}
+
+/// Read a file as changed by the patch stored in another.
+pub fn read_patched_file(filename: &str, patch_filename: &str, format: PatchFormat) -> Result<Vec<u8>> {
+    let source = fs::read(filename)?;
+    let patch = fs::read(patch_filename)?;
+    
+    apply_patch(format, &source, &patch)
+}
+
+/// Create a patch in the given format that turns source into target.
+pub fn create_patch(format: PatchFormat, source: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        PatchFormat::Ips => create_ips(source, target),
+        PatchFormat::Bps => Ok(create_bps(source, target))
+    }
+}
+
+/// Apply a patch in the given format to source, yielding the patched file.
+pub fn apply_patch(format: PatchFormat, source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch)
+    }
+}
+
+/// Compute the CRC-32 of some data, as used by BPS patches, zip files, and
+/// most ROM databases.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        
+        for _ in 0..8 {
+            c = match c & 1 {
+                1 => 0xEDB8_8320 ^ (c >> 1),
+                _ => c >> 1
+            };
+        }
+        
+        *entry = c;
+    }
+    
+    !data.iter().fold(!0u32, |crc, &b| table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+fn corrupt(offset: usize, reason: &str) -> Error {
+    Error::CorruptData { offset: offset as u64, reason: reason.to_string() }
+}
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+
+/// The offset that IPS records cannot start at, since it reads as the EOF
+/// marker.
+const IPS_EOF_OFFSET: usize = 0x454F46;
+
+/// The largest offset an IPS record can start at.
+const IPS_MAX_OFFSET: usize = 0xFF_FFFF;
+
+/// The longest data an IPS record can hold.
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+/// Unchanged bytes shorter than a record header are cheaper to repeat than to
+/// skip by starting a new record.
+const IPS_MERGE_GAP: usize = 5;
+
+/// Runs of a single byte at least this long are stored as RLE records, which
+/// take eight bytes regardless of length.
+const IPS_MIN_RLE: usize = 9;
+
+fn push_ips_header(out: &mut Vec<u8>, offset: usize, size: usize) {
+    out.extend_from_slice(&[(offset >> 16) as u8, (offset >> 8) as u8, offset as u8, (size >> 8) as u8, size as u8]);
+}
+
+/// Store a stretch of target data as IPS records, using RLE records for long
+/// runs of one byte.
+fn push_ips_records(out: &mut Vec<u8>, target: &[u8], start: usize, end: usize) -> Result<()> {
+    let mut pos = start;
+    
+    while pos < end {
+        //Records may not start where they would be mistaken for the end of
+        //the patch, so such a record starts one byte early instead.
+        if pos == IPS_EOF_OFFSET {
+            pos -= 1;
+        }
+        
+        if pos > IPS_MAX_OFFSET {
+            return Err(Error::invalid("IPS patches cannot change data past the first 16MB of a file"));
+        }
+        
+        let run = target[pos..end].iter().take(IPS_MAX_RECORD).take_while(|&&b| b == target[pos]).count();
+        
+        if run >= IPS_MIN_RLE {
+            push_ips_header(out, pos, 0);
+            out.extend_from_slice(&[(run >> 8) as u8, run as u8, target[pos]]);
+            pos += run;
+            continue;
+        }
+        
+        //Literal data stops short of the next run long enough to be RLE.
+        let mut len = 1;
+        while pos + len < end && len < IPS_MAX_RECORD {
+            let b = target[pos + len];
+            
+            if target[pos + len..end].iter().take(IPS_MIN_RLE).take_while(|&&c| c == b).count() >= IPS_MIN_RLE && pos + len != IPS_EOF_OFFSET {
+                break;
+            }
+            
+            len += 1;
+        }
+        
+        push_ips_header(out, pos, len);
+        out.extend_from_slice(&target[pos..pos + len]);
+        pos += len;
+    }
+    
+    Ok(())
+}
+
+/// Create an IPS patch that turns source into target.
+///
+/// Changed data is grouped into records, with RLE records for long runs of
+/// one byte. IPS can only grow files and only reach their first 16MB, so
+/// targets shorter than the source or changed past that point are an error.
+pub fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    if target.len() < source.len() {
+        return Err(Error::invalid("IPS patches cannot make a file shorter"));
+    }
+    
+    let changed = |i: usize| source.get(i) != Some(&target[i]);
+    let mut out = IPS_MAGIC.to_vec();
+    let mut i = 0;
+    
+    while i < target.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+        
+        let start = i;
+        let mut end = i + 1;
+        
+        //Extend the stretch across short gaps of unchanged bytes.
+        loop {
+            match (end..target.len()).find(|&j| changed(j)) {
+                Some(next) if next - end < IPS_MERGE_GAP => end = next + 1,
+                _ => break
+            }
+        }
+        
+        push_ips_records(&mut out, target, start, end)?;
+        i = end;
+    }
+    
+    out.extend_from_slice(IPS_EOF);
+    Ok(out)
+}
+
+/// Apply an IPS patch to source.
+///
+/// Records past the end of the source extend it, filling any gap with zeroes.
+/// The common truncation extension, a three-byte length after the EOF marker,
+/// is honored.
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(corrupt(0, "This is not an IPS patch"));
+    }
+    
+    let mut out = source.to_vec();
+    let mut pos = IPS_MAGIC.len();
+    let read = |pos: usize, len: usize| patch.get(pos..pos + len).ok_or_else(|| corrupt(pos, "The IPS patch ends partway through a record"));
+    let number = |bytes: &[u8]| bytes.iter().fold(0usize, |n, &b| n << 8 | b as usize);
+    
+    loop {
+        let header = read(pos, 3)?;
+        if header == IPS_EOF {
+            if let Ok(length) = read(pos + 3, 3) {
+                out.truncate(number(length));
+            }
+            
+            return Ok(out);
+        }
+        
+        let offset = number(header);
+        let size = number(read(pos + 3, 2)?);
+        pos += 5;
+        
+        let (data, fill) = match size {
+            0 => {
+                let rle = read(pos, 3)?;
+                pos += 3;
+                (&rle[2..], number(&rle[..2]))
+            },
+            size => {
+                let data = read(pos, size)?;
+                pos += size;
+                (data, 1)
+            }
+        };
+        
+        let end = offset + data.len() * fill;
+        if out.len() < end {
+            out.resize(end, 0);
+        }
+        
+        for (dst, &b) in out[offset..end].iter_mut().zip(data.iter().cycle()) {
+            *dst = b;
+        }
+    }
+}
+
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+const BPS_SOURCE_READ: usize = 0;
+const BPS_TARGET_READ: usize = 1;
+const BPS_SOURCE_COPY: usize = 2;
+const BPS_TARGET_COPY: usize = 3;
+
+fn push_bps_number(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let x = (n & 0x7F) as u8;
+        n >>= 7;
+        
+        if n == 0 {
+            out.push(0x80 | x);
+            return;
+        }
+        
+        out.push(x);
+        n -= 1;
+    }
+}
+
+fn read_bps_number(patch: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut n = 0u64;
+    let mut shift = 1u64;
+    
+    loop {
+        let x = match patch.get(*pos) {
+            Some(&x) => x,
+            None => return Err(corrupt(*pos, "The BPS patch ends partway through a number"))
+        };
+        *pos += 1;
+        
+        n = n.checked_add((x & 0x7F) as u64 * shift).ok_or_else(|| corrupt(*pos, "The BPS patch holds a number too large to use"))?;
+        if x & 0x80 != 0 {
+            return Ok(n);
+        }
+        
+        shift = shift.checked_shl(7).ok_or_else(|| corrupt(*pos, "The BPS patch holds a number too large to use"))?;
+        n += shift;
+    }
+}
+
+/// Create a BPS patch that turns source into target.
+///
+/// Bytes that match the source at the same position are read from it, and
+/// everything else is stored in the patch. Runs of one byte are stored once
+/// and copied from the target, the BPS equivalent of an RLE record.
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = BPS_MAGIC.to_vec();
+    let same = |i: usize| source.get(i) == Some(&target[i]);
+    let mut i = 0;
+    
+    push_bps_number(&mut out, source.len() as u64);
+    push_bps_number(&mut out, target.len() as u64);
+    push_bps_number(&mut out, 0);
+    
+    //Target copies start relative to the previous one, which is zero at first.
+    let mut last_copy = 0usize;
+    
+    while i < target.len() {
+        let kind = same(i);
+        let len = (i..target.len()).take_while(|&j| same(j) == kind).count();
+        
+        if kind {
+            push_bps_number(&mut out, ((len as u64 - 1) << 2) | BPS_SOURCE_READ as u64);
+            i += len;
+            continue;
+        }
+        
+        let mut pos = i;
+        let end = i + len;
+        
+        while pos < end {
+            let run = target[pos..end].iter().take_while(|&&b| b == target[pos]).count();
+            let literal = match run {
+                run if run > 3 => 1,
+                _ => (pos..end).take_while(|&j| target[j..end].iter().take(4).take_while(|&&b| b == target[j]).count() < 4 || j == pos).count()
+            };
+            
+            push_bps_number(&mut out, ((literal as u64 - 1) << 2) | BPS_TARGET_READ as u64);
+            out.extend_from_slice(&target[pos..pos + literal]);
+            pos += literal;
+            
+            if run > 3 {
+                //Copying from one byte back repeats that byte.
+                let from = pos - 1;
+                let delta = from as i64 - last_copy as i64;
+                push_bps_number(&mut out, ((run as u64 - 2) << 2) | BPS_TARGET_COPY as u64);
+                push_bps_number(&mut out, (delta.unsigned_abs() << 1) | (delta < 0) as u64);
+                last_copy = from + run - 1;
+                pos += run - 1;
+            }
+        }
+        
+        i = end;
+    }
+    
+    out.extend_from_slice(&crc32(source).to_le_bytes());
+    out.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&out);
+    out.extend_from_slice(&patch_crc.to_le_bytes());
+    
+    out
+}
+
+/// Apply a BPS patch to source.
+///
+/// The source must be the exact file the patch was made from, and the result
+/// must match the checksum the patch records for it; anything else is an
+/// error rather than a quietly broken file.
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if !patch.starts_with(BPS_MAGIC) || patch.len() < BPS_MAGIC.len() + 12 {
+        return Err(corrupt(0, "This is not a BPS patch"));
+    }
+    
+    let footer = patch.len() - 12;
+    let crc_at = |pos: usize| u32::from_le_bytes([patch[pos], patch[pos + 1], patch[pos + 2], patch[pos + 3]]);
+    
+    if crc32(&patch[..footer + 8]) != crc_at(footer + 8) {
+        return Err(corrupt(footer + 8, "The BPS patch is damaged; its checksum does not match"));
+    }
+    
+    let mut pos = BPS_MAGIC.len();
+    let source_size = read_bps_number(patch, &mut pos)? as usize;
+    let target_size = read_bps_number(patch, &mut pos)? as usize;
+    let metadata_size = read_bps_number(patch, &mut pos)? as usize;
+    pos += metadata_size;
+    
+    if source.len() != source_size || crc32(source) != crc_at(footer) {
+        return Err(Error::invalid("This BPS patch was made for a different file"));
+    }
+    
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_rel = 0usize;
+    let mut target_rel = 0usize;
+    
+    while pos < footer {
+        let command_at = pos;
+        let command = read_bps_number(patch, &mut pos)?;
+        let len = (command >> 2) as usize + 1;
+        
+        if out.len() + len > target_size {
+            return Err(corrupt(command_at, "The BPS patch writes past the end of its target"));
+        }
+        
+        let relative = |pos: &mut usize, base: usize| -> Result<usize> {
+            let n = read_bps_number(patch, pos)?;
+            let delta = (n >> 1) as usize;
+            
+            match n & 1 {
+                0 => base.checked_add(delta),
+                _ => base.checked_sub(delta)
+            }.ok_or_else(|| corrupt(command_at, "The BPS patch copies from before the start of a file"))
+        };
+        
+        match command as usize & 3 {
+            BPS_SOURCE_READ => {
+                let at = out.len();
+                let data = source.get(at..at + len).ok_or_else(|| corrupt(command_at, "The BPS patch reads past the end of its source"))?;
+                out.extend_from_slice(data);
+            },
+            BPS_TARGET_READ => {
+                let data = patch.get(pos..pos + len).filter(|_| pos + len <= footer).ok_or_else(|| corrupt(pos, "The BPS patch ends partway through its data"))?;
+                out.extend_from_slice(data);
+                pos += len;
+            },
+            BPS_SOURCE_COPY => {
+                source_rel = relative(&mut pos, source_rel)?;
+                let data = source.get(source_rel..source_rel + len).ok_or_else(|| corrupt(command_at, "The BPS patch copies past the end of its source"))?;
+                out.extend_from_slice(data);
+                source_rel += len;
+            },
+            _ => {
+                target_rel = relative(&mut pos, target_rel)?;
+                
+                //Copies may overlap the data they are producing, so they must
+                //go one byte at a time.
+                for _ in 0..len {
+                    let b = *out.get(target_rel).ok_or_else(|| corrupt(command_at, "The BPS patch copies target data that does not exist yet"))?;
+                    out.push(b);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+    
+    if out.len() != target_size || crc32(&out) != crc_at(footer + 4) {
+        return Err(corrupt(footer + 4, "The BPS patch did not produce the file it describes"));
+    }
+    
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use awsmimg::patch::*;
+    
+    fn sample_files() -> (Vec<u8>, Vec<u8>) {
+        let source : Vec<u8> = (0..0x2000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = source.clone();
+        
+        target[0x10] = 0xFF;
+        target[0x12] = 0xFE;
+        for b in target[0x100..0x180].iter_mut() {
+            *b = 0x55;
+        }
+        for (i, b) in target[0x400..0x420].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        target.extend_from_slice(&[0xAA; 40]);
+        
+        (source, target)
+    }
+    
+    #[test]
+    fn ips_roundtrip() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        
+        let (source, target) = sample_files();
+        let patch = create_ips(&source, &target).unwrap();
+        
+        assert!(patch.starts_with(b"PATCH") && patch.ends_with(b"EOF"));
+        assert!(patch.len() < 100);
+        assert_eq!(apply_ips(&source, &patch).unwrap(), target);
+        assert!(create_ips(&target, &source).is_err());
+        assert!(apply_ips(&source, &patch[..patch.len() - 1]).is_err());
+        
+        //A change at the offset spelling "EOF" must still be recorded.
+        let source = vec![0u8; IPS_EOF_OFFSET + 16];
+        let mut target = source.clone();
+        target[IPS_EOF_OFFSET] = 1;
+        for b in target[IPS_EOF_OFFSET - 12..IPS_EOF_OFFSET].iter_mut() {
+            *b = 2;
+        }
+        let patch = create_ips(&source, &target).unwrap();
+        assert_eq!(apply_ips(&source, &patch).unwrap(), target);
+    }
+    
+    #[test]
+    fn bps_roundtrip() {
+        let (source, target) = sample_files();
+        let patch = create_bps(&source, &target);
+        
+        assert!(patch.starts_with(b"BPS1"));
+        assert!(patch.len() < 100);
+        assert_eq!(apply_bps(&source, &patch).unwrap(), target);
+        assert_eq!(apply_bps(&source, &create_bps(&target, &source)).is_err(), true);
+        assert_eq!(apply_bps(&target, &create_bps(&target, &source)).unwrap(), source);
+        
+        let mut damaged = patch.clone();
+        damaged[10] ^= 1;
+        assert!(apply_bps(&source, &damaged).is_err());
+        assert!(apply_patch(PatchFormat::Bps, &target, &patch).is_err());
+        assert_eq!(patch_format_for_filename("hack.BPS"), Some(PatchFormat::Bps));
+    }
+}
@@ -0,0 +1,248 @@
+use image::{GenericImage, Pixel, Primitive, Rgba};
+use std::collections::BTreeMap;
+
+use awsmimg::conversion::{tiled_indexes_from_rows, rgba8_from_pixel};
+use awsmimg::dither::{Dither, dither_pixels, palette_spread};
+
+/// The result of reducing a truecolor image to an indexed one.
+/// 
+/// indexes is tile-ordered data suitable for IndexedGraphicsEncoder's
+/// encode_indexes, and palette is suitable for its encode_palette. Index 0 of
+/// the palette is always reserved for transparency.
+pub struct QuantizedImage {
+    pub indexes: Vec<u8>,
+    pub palette: Vec<Rgba<u8>>
+}
+
+/// Round an 8-bit color to the nearest color representable in a 15-bit
+/// hardware palette, then expand it back to 8 bits per channel.
+/// 
+/// The expansion matches the one used when decoding hardware palettes, so
+/// colors reduced by this function survive a palette round-trip unchanged.
+pub fn reduce_to_bgr555(color: Rgba<u8>) -> Rgba<u8> {
+    let reduce = |c: u8| -> u8 {
+        let c5 = (c as u32 * 31 + 127) / 255;
+        (c5 << 3 | c5 >> 2) as u8
+    };
+    
+    Rgba([reduce(color[0]), reduce(color[1]), reduce(color[2]), color[3]])
+}
+
+/// Approximate the perceived difference between two colors.
+/// 
+/// This is the "redmean" weighted Euclidean distance, which tracks human color
+/// perception considerably better than plain RGB distance at a fraction of the
+/// cost of converting to a proper perceptual color space.
+pub fn perceptual_distance(a: &Rgba<u8>, b: &Rgba<u8>) -> u32 {
+    let rmean = (a[0] as i32 + b[0] as i32) / 2;
+    let dr = a[0] as i32 - b[0] as i32;
+    let dg = a[1] as i32 - b[1] as i32;
+    let db = a[2] as i32 - b[2] as i32;
+    
+    ((((512 + rmean) * dr * dr) >> 8) + 4 * dg * dg + (((767 - rmean) * db * db) >> 8)) as u32
+}
+
+/// Find the opaque palette entry perceptually closest to a color.
+/// 
+/// Index 0 is reserved for transparency and is never selected unless the
+/// palette contains no other colors.
+pub fn nearest_opaque_index(color: &Rgba<u8>, palette: &[Rgba<u8>]) -> usize {
+    let mut best = 0;
+    let mut best_dist = u32::MAX;
+    
+    for (i, c) in palette.iter().enumerate().skip(1) {
+        let dist = perceptual_distance(color, c);
+        
+        if dist < best_dist {
+            best = i;
+            best_dist = dist;
+        }
+    }
+    
+    best
+}
+
+/// Relative importance of each color channel when deciding where to split.
+const CHANNEL_WEIGHTS: [u32; 3] = [3, 4, 2];
+
+/// A set of colors, and how often each occurs, for median cut quantization.
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>
+}
+
+impl ColorBox {
+    /// Determine the channel with the largest perceptually weighted range,
+    /// along with the size of that range.
+    fn widest_channel(&self) -> (usize, u32) {
+        let mut widest = (0, 0);
+        
+        for (ch, weight) in CHANNEL_WEIGHTS.iter().enumerate() {
+            let min = self.colors.iter().map(|c| c.0[ch]).min().unwrap_or(0);
+            let max = self.colors.iter().map(|c| c.0[ch]).max().unwrap_or(0);
+            let range = (max - min) as u32 * weight;
+            
+            if range > widest.1 {
+                widest = (ch, range);
+            }
+        }
+        
+        widest
+    }
+    
+    /// Split the box in two at the population median of its widest channel.
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (ch, _) = self.widest_channel();
+        self.colors.sort_by_key(|c| c.0[ch]);
+        
+        let total : u64 = self.colors.iter().map(|c| c.1 as u64).sum();
+        let mut running = 0u64;
+        let mut cut = 1;
+        
+        for (i, c) in self.colors.iter().enumerate() {
+            running += c.1 as u64;
+            if running * 2 >= total {
+                cut = i + 1;
+                break;
+            }
+        }
+        
+        if cut >= self.colors.len() {
+            cut = self.colors.len() - 1;
+        }
+        
+        let upper = self.colors.split_off(cut);
+        (self, ColorBox { colors: upper })
+    }
+    
+    /// Compute the population-weighted average color of the box.
+    fn average(&self) -> Rgba<u8> {
+        let mut sums = [0u64; 3];
+        let mut total = 0u64;
+        
+        for &(c, n) in self.colors.iter() {
+            for ch in 0..3 {
+                sums[ch] += c[ch] as u64 * n as u64;
+            }
+            total += n as u64;
+        }
+        
+        let avg = |ch: usize| ((sums[ch] + total / 2) / total) as u8;
+        reduce_to_bgr555(Rgba([avg(0), avg(1), avg(2), 255]))
+    }
+}
+
+/// Build a palette of at most maxcol opaque colors, plus a transparent color
+/// at index 0, that best represents the given color histogram.
+/// 
+/// Histogram keys are expected to already be reduced to hardware precision.
+pub fn median_cut(histogram: BTreeMap<[u8; 3], u32>, maxcol: usize) -> Vec<Rgba<u8>> {
+    let mut palette = vec![Rgba([0u8, 0, 0, 0])];
+    
+    if histogram.len() <= maxcol {
+        palette.extend(histogram.keys().map(|c| Rgba([c[0], c[1], c[2], 255])));
+        return palette;
+    }
+    
+    let mut boxes = vec![ColorBox { colors: histogram.into_iter().collect() }];
+    
+    while boxes.len() < maxcol {
+        let candidate = boxes.iter().enumerate()
+            .filter(|&(_, b)| b.colors.len() > 1)
+            .max_by_key(|&(_, b)| b.widest_channel().1)
+            .map(|(i, _)| i);
+        
+        match candidate {
+            Some(i) => {
+                let (lower, upper) = boxes.swap_remove(i).split();
+                boxes.push(lower);
+                boxes.push(upper);
+            },
+            None => break
+        }
+    }
+    
+    palette.extend(boxes.iter().map(|b| b.average()));
+    palette
+}
+
+/// Given a truecolor image, reduce it to an indexed image with a palette of at
+/// most maxcol + 1 colors using median cut quantization.
+/// 
+/// Colors are first rounded to the 15-bit color depth of the target hardware,
+/// so that no two palette entries are distinguishable only by precision the
+/// hardware lacks. Index 0 is reserved for transparency: fully transparent
+/// pixels map to it, and opaque pixels never do. As with indexes_from_luma,
+/// transparent pixels at the end of the data do not extend its length.
+/// 
+/// Pixels are assigned to the perceptually nearest palette entry, after
+/// applying the requested dithering method.
+pub fn quantize_image<I, P, S>(image: &I, maxcol: u16, tsize: (u32, u32), dither: Dither) -> QuantizedImage
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    
+    let mut histogram = BTreeMap::new();
+    
+    for (_, _, pixel) in image.pixels() {
+        let rgba = reduce_to_bgr555(rgba8_from_pixel(pixel));
+        
+        if rgba[3] != 0 {
+            *histogram.entry([rgba[0], rgba[1], rgba[2]]).or_insert(0u32) += 1;
+        }
+    }
+    
+    let palette = median_cut(histogram, maxcol as usize);
+    let rows = dither_pixels(image, dither, palette_spread(&palette), |c| {
+        let i = nearest_opaque_index(&reduce_to_bgr555(c), &palette);
+        (i as u8, palette[i])
+    });
+    let indexes = tiled_indexes_from_rows(&rows, image.width(), tsize);
+    
+    QuantizedImage {
+        indexes,
+        palette
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::quantize::{quantize_image, reduce_to_bgr555};
+    use awsmimg::dither::Dither;
+    
+    #[test]
+    fn quantize_exact() {
+        let colors = [Rgba([255u8, 0, 0, 255]), Rgba([0u8, 255, 0, 255]), Rgba([0u8, 0, 255, 255]), Rgba([0u8, 0, 0, 0])];
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(8, 8, |x, _| colors[(x / 2) as usize]);
+        
+        let q = quantize_image(&img, 15, (8, 8), Dither::None);
+        
+        assert_eq!(q.indexes.len(), 62);
+        assert_eq!(q.palette.len(), 4);
+        assert_eq!(q.palette[0][3], 0);
+        
+        for y in 0..8 {
+            for x in 0..6 {
+                assert_eq!(q.palette[q.indexes[y * 8 + x] as usize], colors[x / 2]);
+            }
+            for x in 6..8 {
+                assert_eq!(q.indexes.get(y * 8 + x).cloned().unwrap_or(0), 0);
+            }
+        }
+    }
+    
+    #[test]
+    fn quantize_reduces() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, 128u8, 255u8])
+        });
+        
+        let q = quantize_image(&img, 15, (8, 8), Dither::None);
+        
+        assert_eq!(q.palette.len(), 16);
+        assert_eq!(q.indexes.len(), 256);
+        assert!(q.indexes.iter().all(|&i| i != 0 && i < 16));
+        
+        for c in q.palette.iter().skip(1) {
+            assert_eq!(&reduce_to_bgr555(*c), c);
+        }
+    }
+}
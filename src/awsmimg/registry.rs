@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use awsmimg::error::{Error, Result};
+use awsmimg::encoder::DynIndexedGraphicsEncoder;
+use awsmimg::decoder::DynIndexedGraphicsDecoder;
+use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder};
+use awsmimg::formats::layout::{BitLayout, LayoutEncoder};
+
+/// Creates an encoder for an indexed format that writes into the given writer.
+pub type IndexedEncoderFactory = Box<dyn for<'a> Fn(&'a mut dyn Write) -> Box<dyn DynIndexedGraphicsEncoder + 'a>>;
+
+/// Creates a decoder for an indexed format that reads from the given reader.
+pub type IndexedDecoderFactory = Box<dyn for<'a> Fn(&'a mut dyn Read) -> Box<dyn DynIndexedGraphicsDecoder + 'a>>;
+
+struct IndexedFormatEntry {
+    description: String,
+    encoder: IndexedEncoderFactory,
+    decoder: IndexedDecoderFactory
+}
+
+/// A table of image formats, keyed by the names users select them with.
+///
+/// Unlike the IndexedFormat enum, the registry is open: crates linking against
+/// awsmimg may register their own formats alongside the built-in ones and have
+/// them selected by name at runtime. Names are case-insensitive; registering a
+/// name twice replaces the earlier format.
+pub struct FormatRegistry {
+    indexed: BTreeMap<String, IndexedFormatEntry>
+}
+
+impl FormatRegistry {
+    /// Create a registry with no formats in it.
+    pub fn new() -> FormatRegistry {
+        FormatRegistry {
+            indexed: BTreeMap::new()
+        }
+    }
+    
+    /// Create a registry holding every built-in indexed format, under the same
+    /// names interpret_indexed_format_name accepts.
+    pub fn with_builtin_formats() -> FormatRegistry {
+        let mut registry = FormatRegistry::new();
+        
+        registry.register_indexed("agb4", "AGB 4bpp tiles, 8x8, two pixels per byte",
+            |w| Box::new(AGB4Encoder::new(w)),
+            |r| Box::new(AGB4Encoder::new(r)));
+        registry.register_indexed("agb8t", "AGB 8bpp tiles, 8x8, one pixel per byte",
+            |w| Box::new(AGB8Encoder::new_tiled(w)),
+            |r| Box::new(AGB8Encoder::new_tiled(r)));
+        registry.register_indexed("agb8c", "AGB 8bpp chunky pixels, one pixel per byte",
+            |w| Box::new(AGB8Encoder::new_chunky(w)),
+            |r| Box::new(AGB8Encoder::new_chunky(r)));
+        
+        registry
+    }
+    
+    /// Register an indexed format under a name.
+    ///
+    /// The factories are called each time an encoder or decoder is requested,
+    /// and are handed the writer or reader the format's data lives in.
+    pub fn register_indexed<E, D>(&mut self, name: &str, description: &str, encoder: E, decoder: D) where E: for<'a> Fn(&'a mut dyn Write) -> Box<dyn DynIndexedGraphicsEncoder + 'a> + 'static, D: for<'a> Fn(&'a mut dyn Read) -> Box<dyn DynIndexedGraphicsDecoder + 'a> + 'static {
+        self.indexed.insert(name.to_ascii_lowercase(), IndexedFormatEntry {
+            description: description.to_string(),
+            encoder: Box::new(encoder),
+            decoder: Box::new(decoder)
+        });
+    }
+    
+    /// Register an indexed format described by a BitLayout, under the
+    /// layout's own name.
+    pub fn register_layout(&mut self, layout: BitLayout) {
+        let name = layout.name.clone();
+        let description = match layout.description.is_empty() {
+            true => format!("{}bpp {:?} layout", layout.bits_per_pixel, layout.packing).to_lowercase(),
+            false => layout.description.clone()
+        };
+        let decoder_layout = layout.clone();
+        
+        self.register_indexed(&name, &description,
+            move |w| Box::new(LayoutEncoder::new(w, layout.clone())),
+            move |r| Box::new(LayoutEncoder::new(r, decoder_layout.clone())));
+    }
+    
+    /// Whether an indexed format has been registered under this name.
+    pub fn contains_indexed(&self, name: &str) -> bool {
+        self.indexed.contains_key(&name.to_ascii_lowercase())
+    }
+    
+    fn indexed_entry(&self, name: &str) -> Result<&IndexedFormatEntry> {
+        self.indexed.get(&name.to_ascii_lowercase()).ok_or_else(|| Error::UnknownFormat(name.to_string()))
+    }
+    
+    /// Create an encoder for the named indexed format.
+    pub fn indexed_encoder<'a>(&self, name: &str, w: &'a mut dyn Write) -> Result<Box<dyn DynIndexedGraphicsEncoder + 'a>> {
+        Ok((self.indexed_entry(name)?.encoder)(w))
+    }
+    
+    /// Create a decoder for the named indexed format.
+    pub fn indexed_decoder<'a>(&self, name: &str, r: &'a mut dyn Read) -> Result<Box<dyn DynIndexedGraphicsDecoder + 'a>> {
+        Ok((self.indexed_entry(name)?.decoder)(r))
+    }
+    
+    /// List the names and descriptions of every registered indexed format, in
+    /// alphabetical order.
+    pub fn indexed_formats(&self) -> Vec<(&str, &str)> {
+        self.indexed.iter().map(|(name, entry)| (name.as_str(), entry.description.as_str())).collect()
+    }
+}
+
+impl Default for FormatRegistry {
+    fn default() -> FormatRegistry {
+        FormatRegistry::with_builtin_formats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::io::Cursor;
+    use image::Primitive;
+    use awsmimg::formats::IndexedGraphicsProperties;
+    use awsmimg::encoder::IndexedGraphicsEncoder;
+    use awsmimg::decoder::IndexedGraphicsDecoder;
+    use awsmimg::formats::layout::parse_layouts;
+    
+    /// A 2bpp chunky format storing one pixel per byte, as a third party might
+    /// define it.
+    struct Bytes2<'a> {
+        f: &'a mut dyn Write
+    }
+    
+    impl<'a> IndexedGraphicsProperties for Bytes2<'a> {
+        fn tile_size(&self) -> (u32, u32) {
+            (1, 1)
+        }
+        
+        fn attribute_size(&self) -> (u32, u32) {
+            (0, 0)
+        }
+        
+        fn palette_maxcol(&self) -> u16 {
+            3
+        }
+    }
+    
+    impl<'a> IndexedGraphicsEncoder for Bytes2<'a> {
+        fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, _width: u32, _height: u32) -> io::Result<()> {
+            let out : Vec<u8> = data.into_iter().map(|i| i.to_u8().unwrap() & 3).collect();
+            self.f.write_all(&out)
+        }
+        
+        fn encode_palette<T: Primitive>(&mut self, _palette: Vec<image::Rgba<T>>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn builtin_formats() {
+        let registry = FormatRegistry::with_builtin_formats();
+        let names : Vec<&str> = registry.indexed_formats().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["agb4", "agb8c", "agb8t"]);
+        assert!(registry.contains_indexed("AGB4"));
+        assert!(!registry.contains_indexed("agb16"));
+        
+        let mut out = Vec::new();
+        {
+            let mut enc = registry.indexed_encoder("agb4", &mut out).unwrap();
+            assert_eq!(enc.tile_size(), (8, 8));
+            assert_eq!(enc.bits_per_pixel(), 4);
+            enc.encode_indexes(vec![1u8, 2, 3, 4], 4, 1).unwrap();
+        }
+        assert_eq!(out, vec![0x21, 0x43]);
+        
+        let mut src = Cursor::new(out);
+        let mut dec = registry.indexed_decoder("agb4", &mut src).unwrap();
+        let indexes : Vec<u8> = dec.decode_indexes(4).unwrap();
+        assert_eq!(indexes, vec![1, 2, 3, 4]);
+        
+        match registry.indexed_decoder("nonsense", &mut Cursor::new(Vec::new())) {
+            Err(Error::UnknownFormat(name)) => assert_eq!(name, "nonsense"),
+            _ => panic!("Expected an unknown format error")
+        }
+    }
+    
+    #[test]
+    fn custom_format() {
+        let mut registry = FormatRegistry::new();
+        registry.register_indexed("bytes2", "2bpp, one pixel per byte",
+            |w| Box::new(Bytes2 { f: w }),
+            |r| Box::new(AGB8Encoder::new_chunky(r)));
+        
+        let mut out = Vec::new();
+        registry.indexed_encoder("Bytes2", &mut out).unwrap().encode_indexes(vec![1u8, 6, 3], 3, 1).unwrap();
+        assert_eq!(out, vec![1, 2, 3]);
+        assert_eq!(registry.indexed_formats(), vec![("bytes2", "2bpp, one pixel per byte")]);
+        
+        let layouts = parse_layouts("[[layout]]\nname = \"NES\"\nbits_per_pixel = 2\npacking = \"planar\"\nplane_group = 1\n").unwrap();
+        for layout in layouts {
+            registry.register_layout(layout);
+        }
+        
+        let mut out = Vec::new();
+        registry.indexed_encoder("nes", &mut out).unwrap().encode_indexes(vec![3u8; 64], 8, 8).unwrap();
+        assert_eq!(out, vec![0xFF; 16]);
+        assert_eq!(registry.indexed_formats()[1], ("nes", "2bpp planar layout"));
+    }
+}
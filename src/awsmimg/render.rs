@@ -0,0 +1,73 @@
+use image::{ImageBuffer, Rgba};
+
+use awsmimg::tiles::{MapEntry, flip_tile};
+
+/// Combine a set of tiles, a tilemap and a palette into the picture the
+/// hardware would display.
+/// 
+/// map_size is the size of the map in tiles, and tsize the size of each tile
+/// in pixels. Flips are honored. Each map entry's palette bank selects which
+/// bank_size colors of the palette its tile is drawn with; formats whose tiles
+/// index the whole palette at once should pass a bank_size of 0, so that bank
+/// numbers are ignored as they are on hardware.
+/// 
+/// Index 0 of every bank is transparent, as are colors beyond the end of the
+/// palette. Map cells referring to tiles that do not exist are left blank.
+pub fn render_scene(tiles: &[Vec<u8>], map: &[MapEntry], map_size: (u32, u32), tsize: (u32, u32), bank_size: usize, palette: &[Rgba<u8>]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (mw, mh) = map_size;
+    let (tw, th) = tsize;
+    let mut out = ImageBuffer::from_pixel(mw * tw, mh * th, Rgba([0u8, 0, 0, 0]));
+    
+    for (i, entry) in map.iter().enumerate().take((mw * mh) as usize) {
+        let tile = match tiles.get(entry.tile) {
+            Some(t) => flip_tile(t, tsize, entry.hflip, entry.vflip),
+            None => continue
+        };
+        let base = entry.palette as usize * bank_size;
+        let (ox, oy) = ((i as u32 % mw) * tw, (i as u32 / mw) * th);
+        
+        for (p, &index) in tile.iter().enumerate() {
+            if index == 0 {
+                continue;
+            }
+            
+            if let Some(c) = palette.get(base + index as usize) {
+                out.put_pixel(ox + p as u32 % tw, oy + p as u32 / tw, Rgba([c[0], c[1], c[2], 255]));
+            }
+        }
+    }
+    
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use awsmimg::render::render_scene;
+    use awsmimg::tiles::MapEntry;
+    
+    #[test]
+    fn scene_flips_and_banks() {
+        //One 2x2 tile with a single opaque pixel in its top-left corner.
+        let tiles = vec![vec![1u8, 0, 0, 0]];
+        let palette = vec![Rgba([0u8, 0, 0, 0]), Rgba([255u8, 0, 0, 255]), Rgba([0u8, 0, 0, 0]), Rgba([0u8, 0, 255, 255])];
+        let map = vec![MapEntry { tile: 0, hflip: false, vflip: false, palette: 0 },
+                       MapEntry { tile: 0, hflip: true, vflip: true, palette: 1 },
+                       MapEntry { tile: 5, hflip: false, vflip: false, palette: 0 },
+                       MapEntry { tile: 0, hflip: false, vflip: false, palette: 1 }];
+        
+        let scene = render_scene(&tiles, &map, (2, 2), (2, 2), 2, &palette);
+        
+        assert_eq!(scene.dimensions(), (4, 4));
+        assert_eq!(*scene.get_pixel(0, 0), Rgba([255u8, 0, 0, 255]));
+        assert_eq!(*scene.get_pixel(1, 0), Rgba([0u8, 0, 0, 0]));
+        assert_eq!(*scene.get_pixel(3, 1), Rgba([0u8, 0, 255, 255]));
+        assert_eq!(*scene.get_pixel(2, 0), Rgba([0u8, 0, 0, 0]));
+        assert_eq!(*scene.get_pixel(0, 2), Rgba([0u8, 0, 0, 0]));
+        assert_eq!(*scene.get_pixel(2, 2), Rgba([0u8, 0, 255, 255]));
+        
+        let unbanked = render_scene(&tiles, &map, (2, 2), (2, 2), 0, &palette);
+        
+        assert_eq!(*unbanked.get_pixel(2, 2), Rgba([255u8, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,251 @@
+
+use awsmimg::error::{Error, Result};
+use awsmimg::formats::IndexedGraphicsProperties;
+use awsmimg::tiles::{split_tiles, join_tiles};
+
+/// Dimensions of every AGB sprite, in pixels, indexed by OBJ shape and then
+/// OBJ size as they appear in OAM attributes 0 and 1.
+pub const OBJ_SIZES: [[(u32, u32); 4]; 3] = [[(8, 8), (16, 16), (32, 32), (64, 64)],
+                                             [(16, 8), (32, 8), (32, 16), (64, 32)],
+                                             [(8, 16), (8, 32), (16, 32), (32, 64)]];
+
+/// Find the OBJ shape and size values for a sprite of the given dimensions.
+pub fn obj_shape_and_size(width: u32, height: u32) -> Option<(u8, u8)> {
+    for (shape, sizes) in OBJ_SIZES.iter().enumerate() {
+        for (size, &dims) in sizes.iter().enumerate() {
+            if dims == (width, height) {
+                return Some((shape as u8, size as u8));
+            }
+        }
+    }
+    
+    None
+}
+
+/// Parse a sprite size given as WIDTHxHEIGHT, e.g. 32x16.
+pub fn parse_obj_size(size: &str) -> Option<(u32, u32)> {
+    let lower = size.to_ascii_lowercase();
+    let mut parts = lower.split('x');
+    
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(w), Some(h), None) => match (w.trim().parse(), h.trim().parse()) {
+            (Ok(w), Ok(h)) => Some((w, h)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/// How the tiles of a sprite larger than one tile are found in memory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ObjMapping {
+    /// Each sprite's tiles are stored consecutively, row-major.
+    OneDimensional,
+    
+    /// Sprite memory is a grid 32 4bpp tiles wide, and each sprite is a
+    /// rectangle of tiles within that grid.
+    TwoDimensional
+}
+
+pub fn interpret_obj_mapping_name(name: &str) -> Option<ObjMapping> {
+    let name = name.to_ascii_lowercase();
+    
+    match name.as_ref() {
+        "1d" => Some(ObjMapping::OneDimensional),
+        "2d" => Some(ObjMapping::TwoDimensional),
+        _ => None
+    }
+}
+
+/// Describes how the frames of a sprite sheet are stored in sprite memory.
+/// 
+/// Frames are read from the sheet left-to-right, top-to-bottom. In 1D mapping
+/// each frame is stored after the last. In 2D mapping frames are packed
+/// left-to-right into the tile grid, starting a new band of rows whenever the
+/// next frame does not fit.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SpriteLayout {
+    pub obj_size: (u32, u32),
+    pub mapping: ObjMapping,
+    pub tsize: (u32, u32),
+    
+    /// Width of the 2D mapping grid, in tiles of this format.
+    pub grid_width: u32
+}
+
+impl SpriteLayout {
+    /// Create a layout for sprites of the given size in a format with the
+    /// given properties.
+    pub fn new<F>(obj_size: (u32, u32), mapping: ObjMapping, props: &F) -> Result<SpriteLayout> where F: IndexedGraphicsProperties + ?Sized {
+        if obj_shape_and_size(obj_size.0, obj_size.1).is_none() {
+            return Err(Error::invalid(format!("{}x{} is not a valid sprite size", obj_size.0, obj_size.1)));
+        }
+        
+        if props.tile_size() != (8, 8) {
+            return Err(Error::invalid("Sprites require a format with 8x8 tiles"));
+        }
+        
+        //256-color tiles take up two slots of the 2D mapping grid each.
+        let grid_width = match props.palette_maxcol() {
+            maxcol if maxcol < 255 => 32,
+            _ => 16
+        };
+        
+        Ok(SpriteLayout {
+            obj_size,
+            mapping,
+            tsize: props.tile_size(),
+            grid_width
+        })
+    }
+    
+    /// The size of a single frame, in tiles.
+    pub fn frame_tiles(&self) -> (u32, u32) {
+        (self.obj_size.0 / self.tsize.0, self.obj_size.1 / self.tsize.1)
+    }
+    
+    fn frames_per_band(&self) -> u32 {
+        (self.grid_width / self.frame_tiles().0).max(1)
+    }
+    
+    /// Determine which tile of a sprite sheet belongs in each tile slot of
+    /// sprite memory.
+    /// 
+    /// The sheet holds frames frames, frames_wide of them per row. Slots that
+    /// no frame occupies are None.
+    pub fn storage_order(&self, frames: usize, frames_wide: u32) -> Vec<Option<usize>> {
+        let (fw, fh) = self.frame_tiles();
+        let sheet_tiles_wide = frames_wide * fw;
+        let sheet_tile = |f: u32, i: u32, j: u32| (((f / frames_wide) * fh + j) * sheet_tiles_wide + (f % frames_wide) * fw + i) as usize;
+        
+        match self.mapping {
+            ObjMapping::OneDimensional => {
+                let mut out = Vec::with_capacity(frames * (fw * fh) as usize);
+                
+                for f in 0..frames as u32 {
+                    for j in 0..fh {
+                        for i in 0..fw {
+                            out.push(Some(sheet_tile(f, i, j)));
+                        }
+                    }
+                }
+                
+                out
+            },
+            ObjMapping::TwoDimensional => {
+                let per_band = self.frames_per_band();
+                let bands = (frames as u32).div_ceil(per_band);
+                let mut out = vec![None; (bands * fh * self.grid_width) as usize];
+                
+                for f in 0..frames as u32 {
+                    let (band, col) = (f / per_band, (f % per_band) * fw);
+                    
+                    for j in 0..fh {
+                        for i in 0..fw {
+                            out[((band * fh + j) * self.grid_width + col + i) as usize] = Some(sheet_tile(f, i, j));
+                        }
+                    }
+                }
+                
+                out
+            }
+        }
+    }
+}
+
+/// Given the tile-ordered index data of a sprite sheet, reorder its tiles the
+/// way sprite memory stores them.
+/// 
+/// The sheet size must be a multiple of the sprite size.
+pub fn sprite_indexes_from_sheet(indexes: &[u8], sheet_size: (u32, u32), layout: &SpriteLayout) -> Result<Vec<u8>> {
+    let (ow, oh) = layout.obj_size;
+    
+    if !sheet_size.0.is_multiple_of(ow) || !sheet_size.1.is_multiple_of(oh) {
+        return Err(Error::BadDimensions { width: sheet_size.0, height: sheet_size.1, multiple: (ow, oh) });
+    }
+    
+    let (frames_wide, frames_high) = (sheet_size.0 / ow, sheet_size.1 / oh);
+    let mut tiles = split_tiles(indexes, layout.tsize);
+    let blank = vec![0u8; (layout.tsize.0 * layout.tsize.1) as usize];
+    let sheet_tiles = (sheet_size.0 / layout.tsize.0 * sheet_size.1 / layout.tsize.1) as usize;
+    tiles.resize(sheet_tiles, blank.clone());
+    
+    let slots : Vec<Vec<u8>> = layout.storage_order((frames_wide * frames_high) as usize, frames_wide).into_iter().map(|slot| match slot {
+        Some(t) => tiles[t].clone(),
+        None => blank.clone()
+    }).collect();
+    
+    Ok(join_tiles(&slots))
+}
+
+/// Given index data as sprite memory stores it, rebuild a sprite sheet.
+/// 
+/// Frames are arranged in an approximately square sheet. Returns the sheet's
+/// tile-ordered index data and its size in pixels.
+pub fn sheet_indexes_from_sprites(data: &[u8], layout: &SpriteLayout) -> (Vec<u8>, (u32, u32)) {
+    let tiles = split_tiles(data, layout.tsize);
+    let (fw, fh) = layout.frame_tiles();
+    
+    let frames = match layout.mapping {
+        ObjMapping::OneDimensional => (tiles.len() as u32).div_ceil(fw * fh),
+        ObjMapping::TwoDimensional => (tiles.len() as u32).div_ceil(layout.grid_width * fh) * layout.frames_per_band()
+    };
+    let frames_wide = ((frames as f32).sqrt().ceil() as u32).max(1);
+    let frames_high = frames.div_ceil(frames_wide);
+    
+    let blank = vec![0u8; (layout.tsize.0 * layout.tsize.1) as usize];
+    let mut sheet = vec![blank; (frames_wide * frames_high * fw * fh) as usize];
+    
+    for (slot, target) in layout.storage_order(frames as usize, frames_wide).into_iter().enumerate() {
+        if let (Some(t), Some(tile)) = (target, tiles.get(slot)) {
+            sheet[t] = tile.clone();
+        }
+    }
+    
+    (join_tiles(&sheet), (frames_wide * layout.obj_size.0, frames_high * layout.obj_size.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use awsmimg::formats::agb::{AGB4Encoder, AGB8Encoder};
+    use awsmimg::sprites::{SpriteLayout, ObjMapping, obj_shape_and_size, parse_obj_size, sprite_indexes_from_sheet, sheet_indexes_from_sprites};
+    
+    #[test]
+    fn obj_sizes() {
+        assert_eq!(parse_obj_size("32x16"), Some((32, 16)));
+        assert_eq!(obj_shape_and_size(32, 16), Some((1, 2)));
+        assert_eq!(obj_shape_and_size(8, 64), None);
+    }
+    
+    #[test]
+    fn sprite_orders() {
+        let mut f : Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        let agb4 = AGB4Encoder::new(&mut f);
+        
+        //A 32x32 sheet of four 16x16 frames; each tile holds its sheet tile number.
+        let sheet : Vec<u8> = (0..16 * 64).map(|i| (i / 64) as u8).collect();
+        let first_indexes = |data: &[u8]| data.chunks(64).map(|t| t[0]).collect::<Vec<u8>>();
+        
+        let layout1d = SpriteLayout::new((16, 16), ObjMapping::OneDimensional, &agb4).unwrap();
+        let sprites1d = sprite_indexes_from_sheet(&sheet, (32, 32), &layout1d).unwrap();
+        assert_eq!(first_indexes(&sprites1d), vec![0, 1, 4, 5, 2, 3, 6, 7, 8, 9, 12, 13, 10, 11, 14, 15]);
+        assert_eq!(sheet_indexes_from_sprites(&sprites1d, &layout1d), (sheet.clone(), (32, 32)));
+        
+        let layout2d = SpriteLayout::new((16, 16), ObjMapping::TwoDimensional, &agb4).unwrap();
+        let sprites2d = sprite_indexes_from_sheet(&sheet, (32, 32), &layout2d).unwrap();
+        let tiles2d = first_indexes(&sprites2d);
+        assert_eq!(tiles2d.len(), 64);
+        assert_eq!(tiles2d[..8], [0, 1, 2, 3, 8, 9, 10, 11]);
+        assert_eq!(tiles2d[32..40], [4, 5, 6, 7, 12, 13, 14, 15]);
+        
+        //Sixteen frames fit in the grid, so the rebuilt sheet is 4 frames square.
+        let (rebuilt, size) = sheet_indexes_from_sprites(&sprites2d, &layout2d);
+        assert_eq!(size, (64, 64));
+        assert_eq!(first_indexes(&rebuilt)[..8], [0, 1, 2, 3, 8, 9, 10, 11]);
+        
+        let agb8 = AGB8Encoder::new_tiled(&mut f);
+        assert_eq!(SpriteLayout::new((16, 16), ObjMapping::TwoDimensional, &agb8).unwrap().grid_width, 16);
+        assert!(SpriteLayout::new((24, 16), ObjMapping::OneDimensional, &agb8).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use image::{GenericImage, Pixel, Primitive, Rgba};
+use std::collections::BTreeMap;
+
+use awsmimg::error::{Error, Result};
+use awsmimg::conversion::{tiled_indexes_from_rows, rgba8_from_pixel};
+use awsmimg::quantize::{reduce_to_bgr555, median_cut, nearest_opaque_index};
+
+type Histogram = BTreeMap<[u8; 3], u32>;
+
+/// Describes which of several sub-palettes each attribute region of an image
+/// uses, along with the contents of those sub-palettes.
+pub struct SubpaletteAssignment {
+    /// Bank number of each attribute region, ordered left-to-right then
+    /// top-to-bottom.
+    pub banks: Vec<u8>,
+    
+    /// Number of attribute regions in each row of the image.
+    pub regions_wide: u32,
+    
+    /// Size of a single attribute region, in pixels.
+    pub attribute_size: (u32, u32),
+    
+    /// Number of colors in a single hardware palette bank.
+    pub bank_size: usize,
+    
+    /// Colors of each sub-palette. Index 0 of every sub-palette is reserved
+    /// for transparency.
+    pub palettes: Vec<Vec<Rgba<u8>>>
+}
+
+impl SubpaletteAssignment {
+    /// Expand the per-region bank numbers into one bank number per tile.
+    /// 
+    /// Tiles are ordered left-to-right, top-to-bottom, as with index data.
+    /// Attribute regions are expected to be a whole number of tiles in size.
+    pub fn tile_banks(&self, tsize: (u32, u32), image_size: (u32, u32)) -> Vec<u8> {
+        let (tw, th) = tsize;
+        let (aw, ah) = self.attribute_size;
+        let tiles_wide = image_size.0.div_ceil(tw);
+        let tiles_high = image_size.1.div_ceil(th);
+        let mut out = Vec::with_capacity((tiles_wide * tiles_high) as usize);
+        
+        for ty in 0..tiles_high {
+            for tx in 0..tiles_wide {
+                let region = (ty * th / ah) * self.regions_wide + (tx * tw / aw);
+                out.push(self.banks[region as usize]);
+            }
+        }
+        
+        out
+    }
+    
+    /// Concatenate all sub-palettes into a single palette, as it would be laid
+    /// out in hardware palette memory.
+    /// 
+    /// Every sub-palette is padded with black to the bank size so that
+    /// sub-palette N always begins at color N * bank_size.
+    pub fn flattened_palette(&self) -> Vec<Rgba<u8>> {
+        let bank_size = self.bank_size;
+        let mut out = Vec::with_capacity(self.palettes.len() * bank_size);
+        
+        for pal in self.palettes.iter() {
+            out.extend(pal.iter().take(bank_size));
+            for _ in pal.len()..bank_size {
+                out.push(Rgba([0, 0, 0, 255]));
+            }
+        }
+        
+        out
+    }
+}
+
+/// The result of reducing a truecolor image to index data that draws from
+/// several sub-palettes.
+pub struct SubpaletteImage {
+    /// Tile-ordered index data. Each index is relative to the sub-palette
+    /// assigned to the attribute region the pixel lies in.
+    pub indexes: Vec<u8>,
+    pub assignment: SubpaletteAssignment
+}
+
+/// Add every color of one histogram into another.
+fn merge_histogram(into: &mut Histogram, from: &Histogram) {
+    for (color, count) in from.iter() {
+        *into.entry(*color).or_insert(0) += *count;
+    }
+}
+
+/// Count how many distinct colors two histograms would have if merged.
+fn union_size(a: &Histogram, b: &Histogram) -> usize {
+    a.len() + b.keys().filter(|c| !a.contains_key(*c)).count()
+}
+
+/// Reduce a histogram to at most maxcol colors, moving the counts of removed
+/// colors onto the color that replaces them.
+fn reduce_histogram(hist: Histogram, maxcol: usize) -> Histogram {
+    if hist.len() <= maxcol {
+        return hist;
+    }
+    
+    let palette = median_cut(hist.clone(), maxcol);
+    let mut out = Histogram::new();
+    
+    for (color, count) in hist.into_iter() {
+        let nearest = palette[nearest_opaque_index(&Rgba([color[0], color[1], color[2], 255]), &palette)];
+        *out.entry([nearest[0], nearest[1], nearest[2]]).or_insert(0) += count;
+    }
+    
+    out
+}
+
+/// Given a truecolor image, split it into attribute regions and reduce it to
+/// index data drawing from at most max_palettes sub-palettes of maxcol colors
+/// each, plus transparency at index 0 of every sub-palette.
+/// 
+/// Regions with more colors than a single sub-palette can hold are quantized
+/// first. Regions are then packed into sub-palettes greedily, largest first,
+/// choosing the sub-palette that gains the fewest new colors. If more than
+/// max_palettes sub-palettes are needed, the pair sharing the most colors is
+/// merged and re-quantized until the limit is met.
+/// 
+/// The sub-palettes are laid out one after another in a 256-color palette, so
+/// max_palettes may not exceed the number that fit there, which is also the
+/// number of palette banks a map entry can select.
+/// 
+/// Colors are reduced to 15-bit hardware precision. As with quantize_image,
+/// transparent pixels at the end of the data do not extend its length.
+pub fn assign_subpalettes<I, P, S>(image: &I, maxcol: u16, tsize: (u32, u32), asize: (u32, u32), max_palettes: usize) -> Result<SubpaletteImage>
+    where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static {
+    
+    let (width, height) = image.dimensions();
+    let (aw, ah) = asize;
+    let maxcol = maxcol as usize;
+    
+    if aw == 0 || ah == 0 {
+        return Err(Error::invalid("This format does not have color attributes."));
+    }
+    
+    let palette_limit = (256 / (maxcol + 1)).max(1);
+    if max_palettes == 0 || max_palettes > palette_limit {
+        return Err(Error::invalid(format!("The number of sub-palettes must be between 1 and {} for this format.", palette_limit)));
+    }
+    
+    let regions_wide = width.div_ceil(aw);
+    let regions_high = height.div_ceil(ah);
+    let mut regions = vec![Histogram::new(); (regions_wide * regions_high) as usize];
+    
+    for (ix, iy, pixel) in image.pixels() {
+        let rgba = reduce_to_bgr555(rgba8_from_pixel(pixel));
+        
+        if rgba[3] != 0 {
+            let region = ((iy / ah) * regions_wide + ix / aw) as usize;
+            *regions[region].entry([rgba[0], rgba[1], rgba[2]]).or_insert(0) += 1;
+        }
+    }
+    
+    let regions : Vec<Histogram> = regions.into_iter().map(|r| reduce_histogram(r, maxcol)).collect();
+    
+    //Pack regions into sub-palettes, largest regions first.
+    let mut order : Vec<usize> = (0..regions.len()).collect();
+    order.sort_by_key(|&r| regions[r].len());
+    order.reverse();
+    
+    let mut palettes : Vec<Histogram> = Vec::new();
+    let mut members : Vec<Vec<usize>> = Vec::new();
+    
+    for r in order {
+        let best = palettes.iter().enumerate()
+            .map(|(i, p)| (i, union_size(p, &regions[r])))
+            .filter(|&(_, size)| size <= maxcol)
+            .min_by_key(|&(i, size)| (size - palettes[i].len(), i))
+            .map(|(i, _)| i);
+        
+        match best {
+            Some(i) => {
+                merge_histogram(&mut palettes[i], &regions[r]);
+                members[i].push(r);
+            },
+            None => {
+                palettes.push(regions[r].clone());
+                members.push(vec![r]);
+            }
+        }
+    }
+    
+    //Merge sub-palettes until we fit within the hardware limit.
+    while palettes.len() > max_palettes {
+        let mut best = (0, 1, usize::MAX);
+        
+        for i in 0..palettes.len() {
+            for j in i + 1..palettes.len() {
+                let size = union_size(&palettes[i], &palettes[j]);
+                if size < best.2 {
+                    best = (i, j, size);
+                }
+            }
+        }
+        
+        let (i, j, _) = best;
+        let absorbed = palettes.swap_remove(j);
+        let absorbed_members = members.swap_remove(j);
+        
+        merge_histogram(&mut palettes[i], &absorbed);
+        palettes[i] = reduce_histogram(palettes[i].clone(), maxcol);
+        members[i].extend(absorbed_members);
+    }
+    
+    let palettes : Vec<Vec<Rgba<u8>>> = palettes.into_iter().map(|p| median_cut(p, maxcol)).collect();
+    let mut banks = vec![0u8; regions.len()];
+    
+    for (bank, regs) in members.iter().enumerate() {
+        for &r in regs.iter() {
+            banks[r] = bank as u8;
+        }
+    }
+    
+    //Finally, map each pixel onto the sub-palette of its region.
+    let rows : Vec<Option<u8>> = image.pixels().map(|(ix, iy, pixel)| {
+        let rgba = reduce_to_bgr555(rgba8_from_pixel(pixel));
+        let region = ((iy / ah) * regions_wide + ix / aw) as usize;
+        
+        match rgba[3] {
+            0 => None,
+            _ => Some(nearest_opaque_index(&rgba, &palettes[banks[region] as usize]) as u8)
+        }
+    }).collect();
+    
+    Ok(SubpaletteImage {
+        indexes: tiled_indexes_from_rows(&rows, width, tsize),
+        assignment: SubpaletteAssignment {
+            banks,
+            regions_wide,
+            attribute_size: asize,
+            bank_size: maxcol + 1,
+            palettes
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::subpalettes::assign_subpalettes;
+    use awsmimg::quantize::reduce_to_bgr555;
+    
+    #[test]
+    fn subpalette_packing() {
+        //Four tiles: two share a red ramp, two share a blue ramp.
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(32, 8, |x, y| {
+            let shade = ((y % 8) * 32) as u8;
+            match x / 8 {
+                0 | 2 => Rgba([255u8, shade, 0, 255]),
+                _ => Rgba([0u8, shade, 255, 255])
+            }
+        });
+        
+        let result = assign_subpalettes(&img, 15, (8, 8), (8, 8), 16).unwrap();
+        let asgn = &result.assignment;
+        
+        assert_eq!(asgn.palettes.len(), 2);
+        assert_eq!(asgn.banks[0], asgn.banks[2]);
+        assert_eq!(asgn.banks[1], asgn.banks[3]);
+        assert!(asgn.banks[0] != asgn.banks[1]);
+        
+        for tile in 0..4 {
+            let pal = &asgn.palettes[asgn.banks[tile] as usize];
+            for py in 0..8 {
+                let idx = result.indexes[tile * 64 + py * 8];
+                let expected = reduce_to_bgr555(img.get_pixel((tile * 8) as u32, py as u32).clone());
+                assert_eq!(pal[idx as usize], expected);
+            }
+        }
+    }
+    
+    #[test]
+    fn subpalette_merge_limit() {
+        let img : ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_fn(32, 8, |x, y| {
+            Rgba([(x / 8 * 64) as u8, (y * 32) as u8, 0, 255])
+        });
+        
+        assert!(assign_subpalettes(&img, 15, (8, 8), (8, 8), 17).is_err());
+        
+        let result = assign_subpalettes(&img, 15, (8, 8), (8, 8), 1).unwrap();
+        
+        assert_eq!(result.assignment.palettes.len(), 1);
+        assert!(result.assignment.palettes[0].len() <= 16);
+        assert!(result.indexes.iter().all(|&i| i != 0 && i < 16));
+    }
+}
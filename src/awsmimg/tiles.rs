@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::cmp::min;
+
+/// A single cell of a tilemap: which tile it shows, how it is flipped, and
+/// which palette bank colors it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MapEntry {
+    pub tile: usize,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub palette: u8
+}
+
+/// A set of unique tiles and a map placing them on a grid.
+///
+/// Each tile holds the tile-ordered index data of a single tile. Map cells are
+/// ordered left-to-right, top-to-bottom, matching the order tiles appear in an
+/// image's index data.
+pub struct Tileset {
+    pub tiles: Vec<Vec<u8>>,
+    pub map: Vec<MapEntry>
+}
+
+/// Split tile-ordered index data into individual tiles.
+///
+/// If the data does not end on a tile boundary, the final tile is padded with
+/// index 0.
+pub fn split_tiles(indexes: &[u8], tsize: (u32, u32)) -> Vec<Vec<u8>> {
+    let tlen = (tsize.0 * tsize.1) as usize;
+    
+    indexes.chunks(tlen).map(|chunk| {
+        let mut tile = chunk.to_vec();
+        tile.resize(tlen, 0);
+        tile
+    }).collect()
+}
+
+/// Produce a mirrored copy of a tile.
+pub fn flip_tile(tile: &[u8], tsize: (u32, u32), hflip: bool, vflip: bool) -> Vec<u8> {
+    let (tw, th) = (tsize.0 as usize, tsize.1 as usize);
+    let mut out = Vec::with_capacity(tile.len());
+    
+    for y in 0..th {
+        let sy = if vflip { th - 1 - y } else { y };
+        
+        for x in 0..tw {
+            let sx = if hflip { tw - 1 - x } else { x };
+            out.push(tile[sy * tw + sx]);
+        }
+    }
+    
+    out
+}
+
+/// Given tile-ordered index data, find every distinct tile and build a map
+/// that reproduces the original data from them.
+///
+/// Tiles are numbered in order of first appearance, and every map entry uses
+/// palette bank 0. If allow_flips is true, a tile which is a horizontally,
+/// vertically, or doubly mirrored copy of an earlier tile is stored as a
+/// flipped reference to that tile rather than as a new tile. Unflipped matches
+/// are always preferred.
+pub fn deduplicate_tiles(indexes: &[u8], tsize: (u32, u32), allow_flips: bool) -> Tileset {
+    let mut tiles : Vec<Vec<u8>> = Vec::new();
+    let mut map = Vec::new();
+    let mut known : HashMap<Vec<u8>, usize> = HashMap::new();
+    let flips : &[(bool, bool)] = match allow_flips {
+        true => &[(false, false), (true, false), (false, true), (true, true)],
+        false => &[(false, false)]
+    };
+    
+    for tile in split_tiles(indexes, tsize) {
+        let found = flips.iter().filter_map(|&(h, v)| {
+            known.get(&flip_tile(&tile, tsize, h, v)).map(|&t| MapEntry { tile: t, hflip: h, vflip: v, palette: 0 })
+        }).next();
+        
+        match found {
+            Some(entry) => map.push(entry),
+            None => {
+                let id = tiles.len();
+                known.insert(tile.clone(), id);
+                tiles.push(tile);
+                map.push(MapEntry { tile: id, hflip: false, vflip: false, palette: 0 });
+            }
+        }
+    }
+    
+    Tileset { tiles, map }
+}
+
+/// Concatenate tiles back into a single stream of tile-ordered index data.
+pub fn join_tiles(tiles: &[Vec<u8>]) -> Vec<u8> {
+    tiles.iter().flat_map(|t| t.iter().cloned()).collect()
+}
+
+/// Given a set of tiles and a map, reproduce the tile-ordered index data the
+/// map describes.
+///
+/// Map cells referring to tiles that do not exist yield blank tiles of index
+/// 0, as they would on hardware with empty tile memory.
+pub fn indexes_from_map(tiles: &[Vec<u8>], map: &[MapEntry], tsize: (u32, u32)) -> Vec<u8> {
+    let tlen = (tsize.0 * tsize.1) as usize;
+    let blank = vec![0u8; tlen];
+    let mut out = Vec::with_capacity(map.len() * tlen);
+    
+    for entry in map.iter() {
+        let tile = tiles.get(entry.tile).unwrap_or(&blank);
+        out.extend(flip_tile(tile, tsize, entry.hflip, entry.vflip));
+    }
+    
+    out
+}
+
+/// Given which tiles of some encoded data should be written, find the byte
+/// ranges of that data to write.
+/// 
+/// Each range covers a run of consecutive visible tiles, so that the data can
+/// be written with as few seeks as possible. Ranges never extend past len, the
+/// length of the encoded data, which may end partway through the tiles.
+pub fn visible_spans(visible: &[bool], tile_bytes: usize, len: usize) -> Vec<Range<usize>> {
+    let mut out : Vec<Range<usize>> = Vec::new();
+    
+    for (tile, _) in visible.iter().enumerate().filter(|&(_, &v)| v) {
+        let start = min(tile * tile_bytes, len);
+        let end = min(start + tile_bytes, len);
+        
+        if start == end {
+            break;
+        }
+        
+        match out.last_mut() {
+            Some(span) if span.end == start => span.end = end,
+            _ => out.push(start..end)
+        }
+    }
+    
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::conversion::visible_tiles;
+    use awsmimg::tiles::{deduplicate_tiles, flip_tile, indexes_from_map, join_tiles, visible_spans, MapEntry};
+    
+    #[test]
+    fn sparse_tiles() {
+        let mut img = ImageBuffer::from_pixel(32, 16, Rgba([0u8, 0, 0, 0]));
+        img.put_pixel(3, 3, Rgba([255, 255, 255, 255]));
+        img.put_pixel(12, 1, Rgba([0, 0, 0, 255]));
+        img.put_pixel(31, 2, Rgba([0, 0, 0, 128]));
+        img.put_pixel(0, 8, Rgba([0, 0, 0, 255]));
+        
+        let visible = visible_tiles(&img, (8, 8));
+        assert_eq!(visible, vec![true, true, false, true, true, false, false, false]);
+        assert_eq!(visible_spans(&visible, 32, 256), vec![0..64, 96..160]);
+        assert_eq!(visible_spans(&visible, 32, 144), vec![0..64, 96..144]);
+    }
+    
+    #[test]
+    fn dedupe_with_flips() {
+        let tile : Vec<u8> = (0..64).map(|i| (i % 16) as u8).collect();
+        let mut indexes = tile.clone();
+        indexes.extend(flip_tile(&tile, (8, 8), true, false));
+        indexes.extend(flip_tile(&tile, (8, 8), false, true));
+        indexes.extend(flip_tile(&tile, (8, 8), true, true));
+        indexes.extend(vec![0u8; 64]);
+        indexes.extend(tile.clone());
+        
+        let set = deduplicate_tiles(&indexes, (8, 8), true);
+        
+        assert_eq!(set.tiles.len(), 2);
+        assert_eq!(set.map, vec![MapEntry { tile: 0, hflip: false, vflip: false, palette: 0 },
+                                 MapEntry { tile: 0, hflip: true, vflip: false, palette: 0 },
+                                 MapEntry { tile: 0, hflip: false, vflip: true, palette: 0 },
+                                 MapEntry { tile: 0, hflip: true, vflip: true, palette: 0 },
+                                 MapEntry { tile: 1, hflip: false, vflip: false, palette: 0 },
+                                 MapEntry { tile: 0, hflip: false, vflip: false, palette: 0 }]);
+        assert_eq!(indexes_from_map(&set.tiles, &set.map, (8, 8)), indexes);
+        
+        let noflip = deduplicate_tiles(&indexes, (8, 8), false);
+        
+        assert_eq!(noflip.tiles.len(), 5);
+        assert_eq!(join_tiles(&noflip.tiles)[..64], tile[..]);
+        assert_eq!(indexes_from_map(&noflip.tiles, &noflip.map, (8, 8)), indexes);
+    }
+}
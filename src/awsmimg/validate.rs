@@ -0,0 +1,192 @@
+use std::fmt;
+use std::collections::BTreeMap;
+use image::{GenericImage, Pixel, Primitive, Rgba};
+
+use awsmimg::formats::IndexedGraphicsProperties;
+use awsmimg::encoder::IndexSource;
+use awsmimg::conversion::{rgba8_from_pixel, luma_index};
+
+/// A single way in which an image fails to fit the format it is being encoded
+/// into.
+///
+/// Pixel violations carry both the pixel's coordinates within the image and
+/// the coordinates of the tile it falls in, counted in tiles.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Violation {
+    /// The image is not a whole number of tiles.
+    Dimensions {
+        width: u32,
+        height: u32,
+        tsize: (u32, u32)
+    },
+    
+    /// A pixel of an image read as grayscale indexes is not a shade of gray.
+    NotGray {
+        x: u32,
+        y: u32,
+        tile: (u32, u32),
+        color: [u8; 3]
+    },
+    
+    /// A pixel's color does not appear in the palette at all.
+    NotInPalette {
+        x: u32,
+        y: u32,
+        tile: (u32, u32),
+        color: [u8; 3]
+    },
+    
+    /// A pixel's color appears in the palette, but only at an index the
+    /// format cannot store.
+    IndexTooHigh {
+        x: u32,
+        y: u32,
+        tile: (u32, u32),
+        index: usize,
+        maxcol: u16
+    },
+    
+    /// Two shades of gray in an image read as grayscale indexes become the
+    /// same index, so that the format cannot tell them apart.
+    ShadesMerged {
+        shade: u8,
+        other: u8,
+        index: u16
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::Dimensions { width, height, tsize: (tw, th) } => write!(f, "Image is {}x{}, which is not a multiple of the {}x{} tile size", width, height, tw, th),
+            Violation::NotGray { x, y, tile: (tx, ty), color } => write!(f, "Pixel at ({}, {}) in tile ({}, {}) has color #{:02X}{:02X}{:02X}, which is not a shade of gray", x, y, tx, ty, color[0], color[1], color[2]),
+            Violation::NotInPalette { x, y, tile: (tx, ty), color } => write!(f, "Pixel at ({}, {}) in tile ({}, {}) has color #{:02X}{:02X}{:02X}, which is not in the palette", x, y, tx, ty, color[0], color[1], color[2]),
+            Violation::IndexTooHigh { x, y, tile: (tx, ty), index, maxcol } => write!(f, "Pixel at ({}, {}) in tile ({}, {}) uses palette index {}, but the format only stores indexes up to {}", x, y, tx, ty, index, maxcol),
+            Violation::ShadesMerged { shade, other, index } => write!(f, "Shade #{:02X} becomes index {}, the same as shade #{:02X}", shade, index, other)
+        }
+    }
+}
+
+/// Check an image against every constraint a format with the given
+/// properties places on it, given how its pixels will become indexes.
+///
+/// Every violation is reported, in row-major pixel order after any whole-image
+/// problems, so that they can all be fixed at once. Fully transparent pixels
+/// are never in violation, as they are not encoded. Sources that generate
+/// their own palette can represent any color and only have the image's
+/// dimensions checked.
+pub fn validate_image<'p, F, I, P, S>(props: &F, image: &I, source: &IndexSource<'p>) -> Vec<Violation> where I: GenericImage<Pixel=P>, P: Pixel<Subpixel=S> + 'static, S: Primitive + 'static, F: IndexedGraphicsProperties + ?Sized {
+    let (width, height) = image.dimensions();
+    let (tw, th) = props.tile_size();
+    let maxcol = props.palette_maxcol();
+    let mut out = Vec::new();
+    let mut pixels = Vec::new();
+    let mut shades = BTreeMap::new();
+    
+    if !width.is_multiple_of(tw) || !height.is_multiple_of(th) {
+        out.push(Violation::Dimensions { width, height, tsize: (tw, th) });
+    }
+    
+    for (x, y, pixel) in image.pixels() {
+        let rgba : Rgba<u8> = rgba8_from_pixel(pixel);
+        let color = [rgba[0], rgba[1], rgba[2]];
+        let tile = (x / tw, y / th);
+        
+        if rgba[3] == 0 {
+            continue;
+        }
+        
+        match *source {
+            IndexSource::Luma => match color[0] == color[1] && color[1] == color[2] {
+                true => {
+                    let gray = rgba.to_luma()[0];
+                    shades.insert(color[0], luma_index(gray as f32, 255.0, maxcol as f32) as u16);
+                },
+                false => pixels.push((y, x, Violation::NotGray { x, y, tile, color }))
+            },
+            IndexSource::Palette(palette, false) => match palette.iter().position(|c| c[0] == color[0] && c[1] == color[1] && c[2] == color[2]) {
+                Some(index) if index > maxcol as usize => pixels.push((y, x, Violation::IndexTooHigh { x, y, tile, index, maxcol })),
+                Some(_) => {},
+                None => pixels.push((y, x, Violation::NotInPalette { x, y, tile, color }))
+            },
+            _ => {}
+        }
+    }
+    
+    //Each index belongs to the darkest shade that becomes it; any lighter
+    //shade that becomes it too is lost.
+    let mut owners = BTreeMap::new();
+    for (&shade, &index) in shades.iter() {
+        match owners.get(&index) {
+            Some(&other) => out.push(Violation::ShadesMerged { shade, other, index }),
+            None => {
+                owners.insert(index, shade);
+            }
+        }
+    }
+    
+    //Images may iterate their pixels in any order, but reports read best
+    //top-to-bottom.
+    pixels.sort_by_key(|&(y, x, _)| (y, x));
+    out.extend(pixels.into_iter().map(|(_, _, v)| v));
+    
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use awsmimg::encoder::IndexSource;
+    use awsmimg::formats::agb::AGB4Encoder;
+    use awsmimg::validate::{validate_image, Violation};
+    
+    #[test]
+    fn strict_violations() {
+        let mut out : Vec<u8> = Vec::new();
+        let props = AGB4Encoder::new(&mut out);
+        let mut img = ImageBuffer::from_fn(12, 8, |x, _| {
+            let shade = (x * 17) as u8;
+            Rgba([shade, shade, shade, 255])
+        });
+        img.put_pixel(3, 5, Rgba([255, 0, 0, 255]));
+        img.put_pixel(10, 2, Rgba([0, 255, 0, 255]));
+        img.put_pixel(11, 2, Rgba([0, 255, 0, 0]));
+        
+        let violations = validate_image(&props, &img, &IndexSource::Luma);
+        assert_eq!(violations, vec![
+            Violation::Dimensions { width: 12, height: 8, tsize: (8, 8) },
+            Violation::NotGray { x: 10, y: 2, tile: (1, 0), color: [0, 255, 0] },
+            Violation::NotGray { x: 3, y: 5, tile: (0, 0), color: [255, 0, 0] }
+        ]);
+        assert_eq!(violations[2].to_string(), "Pixel at (3, 5) in tile (0, 0) has color #FF0000, which is not a shade of gray");
+        
+        let gradient = ImageBuffer::from_fn(32, 8, |x, _| {
+            let shade = (x * 8) as u8;
+            Rgba([shade, shade, shade, 255])
+        });
+        let merged = validate_image(&props, &gradient, &IndexSource::Luma);
+        assert_eq!(merged.len(), 17);
+        assert_eq!(merged[0], Violation::ShadesMerged { shade: 0x08, other: 0x00, index: 0 });
+        assert_eq!(merged[0].to_string(), "Shade #08 becomes index 0, the same as shade #00");
+        
+        //Sixteen shades can still be too close together to tell apart.
+        let dark = ImageBuffer::from_fn(16, 8, |x, _| Rgba([x as u8, x as u8, x as u8, 255]));
+        assert_eq!(validate_image(&props, &dark, &IndexSource::Luma).len(), 15);
+        
+        let ramp = ImageBuffer::from_fn(16, 8, |x, _| Rgba([x as u8 * 17, x as u8 * 17, x as u8 * 17, 255]));
+        assert!(validate_image(&props, &ramp, &IndexSource::Luma).is_empty());
+        assert!(validate_image(&props, &gradient, &IndexSource::Subpalettes(4)).is_empty());
+        
+        let mut palette : Vec<Rgba<u8>> = (0..20).map(|i| Rgba([i, 0, 0, 255])).collect();
+        palette.push(Rgba([0, 0, 255, 255]));
+        let mut mapped = ImageBuffer::from_pixel(8, 8, Rgba([1u8, 0, 0, 255]));
+        mapped.put_pixel(7, 0, Rgba([18, 0, 0, 255]));
+        mapped.put_pixel(0, 1, Rgba([0, 9, 0, 255]));
+        
+        assert_eq!(validate_image(&props, &mapped, &IndexSource::Palette(&palette, false)), vec![
+            Violation::IndexTooHigh { x: 7, y: 0, tile: (0, 0), index: 18, maxcol: 15 },
+            Violation::NotInPalette { x: 0, y: 1, tile: (0, 0), color: [0, 9, 0] }
+        ]);
+        assert!(validate_image(&props, &mapped, &IndexSource::Palette(&palette, true)).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use image::{Primitive, Rgba};
+
+use awsmimg::error::Result;
+use awsmimg::formats::IndexedGraphicsProperties;
+use awsmimg::encoder::IndexedGraphicsEncoder;
+use awsmimg::decoder::IndexedGraphicsDecoder;
+
+/// An encoder that keeps a copy of every index it is asked to encode, so that
+/// the encoded data can later be checked against it.
+///
+/// Indexes are recorded in the order they were written, after any reordering
+/// done by the caller, such as tile deduplication or sprite ordering. That is
+/// exactly the order a decoder reading the same region will produce them in.
+pub struct RecordingEncoder<E> {
+    enc: E,
+    indexes: Vec<u8>
+}
+
+impl<E> RecordingEncoder<E> where E: IndexedGraphicsEncoder {
+    pub fn new(enc: E) -> RecordingEncoder<E> {
+        RecordingEncoder {
+            enc,
+            indexes: Vec::new()
+        }
+    }
+    
+    /// Every index encoded so far.
+    pub fn indexes(&self) -> &[u8] {
+        &self.indexes
+    }
+    
+    /// Give up the wrapped encoder, keeping only the indexes it encoded.
+    pub fn into_indexes(self) -> Vec<u8> {
+        self.indexes
+    }
+}
+
+impl<E> IndexedGraphicsProperties for RecordingEncoder<E> where E: IndexedGraphicsEncoder {
+    fn tile_size(&self) -> (u32, u32) {
+        self.enc.tile_size()
+    }
+    
+    fn attribute_size(&self) -> (u32, u32) {
+        self.enc.attribute_size()
+    }
+    
+    fn palette_maxcol(&self) -> u16 {
+        self.enc.palette_maxcol()
+    }
+    
+    fn bits_per_pixel(&self) -> u32 {
+        self.enc.bits_per_pixel()
+    }
+}
+
+impl<E> IndexedGraphicsEncoder for RecordingEncoder<E> where E: IndexedGraphicsEncoder {
+    fn encode_indexes<P: Primitive>(&mut self, data: Vec<P>, width: u32, height: u32) -> io::Result<()> {
+        self.indexes.extend(data.iter().map(|index| index.to_u8().unwrap_or(u8::MAX)));
+        self.enc.encode_indexes(data, width, height)
+    }
+    
+    fn encode_palette<T: Primitive>(&mut self, palette: Vec<Rgba<T>>) -> io::Result<()> {
+        self.enc.encode_palette(palette)
+    }
+}
+
+/// A tile that did not decode to the indexes it was encoded from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TileMismatch {
+    /// Which tile differs, counting from the first tile decoded.
+    pub tile: usize,
+    
+    /// Where the tile's data begins, in bytes from the start of the decoded
+    /// region.
+    pub offset: u64,
+    
+    /// How many of the tile's pixels differ. Pixels missing from the decoded
+    /// data count as differing.
+    pub pixels: usize
+}
+
+/// Decode as many indexes as were expected and compare them tile by tile,
+/// listing every tile that differs.
+///
+/// A decoder that runs out of data early leaves the remaining tiles
+/// mismatched rather than yielding an error, so that truncated output is
+/// reported the same way as corrupted output.
+pub fn verify_indexes<D>(dec: &mut D, expected: &[u8]) -> Result<Vec<TileMismatch>> where D: IndexedGraphicsDecoder + ?Sized {
+    let (tw, th) = dec.tile_size();
+    let tile_pixels = (tw * th) as usize;
+    let tile_bytes = (tile_pixels * dec.bits_per_pixel() as usize / 8) as u64;
+    let actual : Vec<u8> = dec.decode_indexes(expected.len())?;
+    let mut out = Vec::new();
+    
+    for (tile, want) in expected.chunks(tile_pixels).enumerate() {
+        let start = tile * tile_pixels;
+        let got = actual.get(start..).unwrap_or(&[]);
+        let pixels = want.iter().enumerate().filter(|&(i, index)| got.get(i) != Some(index)).count();
+        
+        if pixels > 0 {
+            out.push(TileMismatch { tile, offset: tile as u64 * tile_bytes, pixels });
+        }
+    }
+    
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use awsmimg::encoder::IndexedGraphicsEncoder;
+    use awsmimg::formats::agb::AGB4Encoder;
+    use awsmimg::verify::{RecordingEncoder, TileMismatch, verify_indexes};
+    
+    #[test]
+    fn verify_roundtrip() {
+        let data : Vec<u8> = (0..192).map(|i| (i % 16) as u8).collect();
+        let mut out = Vec::new();
+        let expected = {
+            let mut enc = RecordingEncoder::new(AGB4Encoder::new(&mut out));
+            enc.encode_indexes(data.clone(), 8, 24).unwrap();
+            enc.indexes().to_vec()
+        };
+        assert_eq!(expected, data);
+        assert!(verify_indexes(&mut AGB4Encoder::new(&mut Cursor::new(out.clone())), &expected).unwrap().is_empty());
+        
+        //Damage two pixels of the second tile and cut the third tile short.
+        out[32] ^= 0x11;
+        out.truncate(80);
+        
+        assert_eq!(verify_indexes(&mut AGB4Encoder::new(&mut Cursor::new(out)), &expected).unwrap(), vec![
+            TileMismatch { tile: 1, offset: 32, pixels: 2 },
+            TileMismatch { tile: 2, offset: 64, pixels: 32 }
+        ]);
+    }
+    
+    #[test]
+    fn verify_odd_length() {
+        let data : Vec<u8> = (0..63).map(|i| (i % 15 + 1) as u8).collect();
+        let mut out = Vec::new();
+        AGB4Encoder::new(&mut out).encode_indexes(data.clone(), 8, 8).unwrap();
+        
+        assert_eq!(out.len(), 32);
+        assert_eq!(out[31], 0x03);
+        assert!(verify_indexes(&mut AGB4Encoder::new(&mut Cursor::new(out)), &data).unwrap().is_empty());
+    }
+}
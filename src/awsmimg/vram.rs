@@ -0,0 +1,190 @@
+use std::io;
+use std::io::Cursor;
+use image::{ImageBuffer, LumaA, Rgba};
+
+use awsmimg::error::Result;
+use awsmimg::address::parse_number;
+use awsmimg::formats::{IndexedGraphicsProperties, MapFormat};
+use awsmimg::formats::agbmap::AGBMapEncoder;
+use awsmimg::decoder::{MapDecoder, decode_tilemap_as_image, decode_scene_as_image};
+use awsmimg::registry::FormatRegistry;
+
+/// Size of the AGB's video memory, as exported by emulators.
+pub const VRAM_SIZE: usize = 0x18000;
+
+/// Distance between the start of each character (tile) base block.
+pub const CHARBLOCK_SIZE: usize = 0x4000;
+
+/// Distance between the start of each screen (map) base block.
+pub const SCREENBLOCK_SIZE: usize = 0x800;
+
+/// Where sprite tiles begin within video memory. Backgrounds cannot display
+/// tiles stored at or past this point.
+pub const OBJ_VRAM_OFFSET: usize = 0x10000;
+
+/// Where sprite colors begin within palette memory, counted in colors.
+/// Background colors are stored at the start of palette memory.
+pub const OBJ_FIRST_COLOR: usize = 0x100;
+
+/// The settings of a background, as stored in one of the BGxCNT registers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BackgroundControl {
+    /// Which charblock the background's tiles start in.
+    pub char_base: u8,
+    
+    /// Whether text backgrounds use 8bpp tiles instead of 4bpp tiles.
+    pub colors256: bool,
+    
+    /// Which screenblock the background's map starts in.
+    pub screen_base: u8,
+    
+    /// The background size setting. Its meaning differs between text and
+    /// affine backgrounds.
+    pub size: u8
+}
+
+impl BackgroundControl {
+    pub fn from_bgcnt(bgcnt: u16) -> BackgroundControl {
+        BackgroundControl {
+            char_base: ((bgcnt >> 2) & 0x3) as u8,
+            colors256: bgcnt & 0x80 != 0,
+            screen_base: ((bgcnt >> 8) & 0x1F) as u8,
+            size: (bgcnt >> 14) as u8
+        }
+    }
+    
+    /// Where the background's tiles start within video memory.
+    pub fn char_offset(&self) -> usize {
+        self.char_base as usize * CHARBLOCK_SIZE
+    }
+    
+    /// Where the background's map starts within video memory.
+    pub fn screen_offset(&self) -> usize {
+        self.screen_base as usize * SCREENBLOCK_SIZE
+    }
+    
+    /// The size of the background's map, in tiles.
+    pub fn map_size(&self, affine: bool) -> (u32, u32) {
+        match (affine, self.size) {
+            (true, s) => (16 << s, 16 << s),
+            (false, 0) => (32, 32),
+            (false, 1) => (64, 32),
+            (false, 2) => (32, 64),
+            (false, _) => (64, 64)
+        }
+    }
+    
+    /// The registry name of the background's tile format. Affine backgrounds
+    /// always use 8bpp tiles.
+    pub fn tile_format(&self, affine: bool) -> &'static str {
+        match affine || self.colors256 {
+            true => "agb8t",
+            false => "agb4"
+        }
+    }
+    
+    pub fn map_format(&self, affine: bool) -> MapFormat {
+        match affine {
+            true => MapFormat::AGBAffine,
+            false => MapFormat::AGBText
+        }
+    }
+}
+
+/// Parse a register value given either in decimal or as 0x-prefixed hex.
+pub fn parse_register(value: &str) -> Option<u16> {
+    match parse_number(value) {
+        Some(number) if number <= u16::MAX as u64 => Some(number as u16),
+        _ => None
+    }
+}
+
+fn vram_region(vram: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    match vram.get(start..end.min(vram.len())) {
+        Some(region) => Ok(region),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "VRAM dump is too short to hold the requested data").into())
+    }
+}
+
+/// Retrieve the region of a VRAM dump a background can draw tiles from.
+pub fn background_tiles<'a>(vram: &'a [u8], bg: &BackgroundControl) -> Result<&'a [u8]> {
+    vram_region(vram, bg.char_offset(), OBJ_VRAM_OFFSET)
+}
+
+/// Retrieve the region of a VRAM dump holding sprite tiles.
+pub fn object_tiles(vram: &[u8]) -> Result<&[u8]> {
+    vram_region(vram, OBJ_VRAM_OFFSET, VRAM_SIZE)
+}
+
+/// Given a VRAM dump and the settings of a background, reconstruct the
+/// background with color indicies represented as grayscale values.
+pub fn decode_vram_background_as_image(registry: &FormatRegistry, vram: &[u8], bg: &BackgroundControl, affine: bool) -> Result<Box<ImageBuffer<LumaA<u8>, Vec<u8>>>> {
+    let tiles = background_tiles(vram, bg)?;
+    let (mw, mh) = bg.map_size(affine);
+    let mut map_data = Cursor::new(vram_region(vram, bg.screen_offset(), OBJ_VRAM_OFFSET)?);
+    let map = AGBMapEncoder::new(&mut map_data, bg.map_format(affine)).decode_map(mw, mh)?;
+    
+    let mut tile_data = Cursor::new(tiles);
+    let mut dec = registry.indexed_decoder(bg.tile_format(affine), &mut tile_data)?;
+    let pixels = tiles.len() * 8 / dec.bits_per_pixel() as usize;
+    
+    decode_tilemap_as_image(&mut dec, pixels, &map, (mw, mh))
+}
+
+/// Given a VRAM dump, the settings of a background, and the background
+/// palette, reconstruct the background as the hardware would display it.
+pub fn decode_vram_background_as_scene(registry: &FormatRegistry, vram: &[u8], bg: &BackgroundControl, affine: bool, palette: &[Rgba<u8>]) -> Result<Box<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+    let tiles = background_tiles(vram, bg)?;
+    let mut map_data = Cursor::new(vram_region(vram, bg.screen_offset(), OBJ_VRAM_OFFSET)?);
+    let mut mapdec = AGBMapEncoder::new(&mut map_data, bg.map_format(affine));
+    
+    let mut tile_data = Cursor::new(tiles);
+    let mut dec = registry.indexed_decoder(bg.tile_format(affine), &mut tile_data)?;
+    let pixels = tiles.len() * 8 / dec.bits_per_pixel() as usize;
+    
+    decode_scene_as_image(&mut dec, pixels, &mut mapdec, bg.map_size(affine), palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use awsmimg::vram::{BackgroundControl, decode_vram_background_as_scene, parse_register, VRAM_SIZE};
+    use awsmimg::registry::FormatRegistry;
+    
+    #[test]
+    fn bgcnt_fields() {
+        let bg = BackgroundControl::from_bgcnt(parse_register("0x5F84").unwrap());
+        
+        assert_eq!(bg, BackgroundControl { char_base: 1, colors256: true, screen_base: 31, size: 1 });
+        assert_eq!(bg.char_offset(), 0x4000);
+        assert_eq!(bg.screen_offset(), 0xF800);
+        assert_eq!(bg.map_size(false), (64, 32));
+        assert_eq!(bg.map_size(true), (32, 32));
+        assert_eq!(parse_register("7936"), Some(0x1F00));
+    }
+    
+    #[test]
+    fn vram_background() {
+        //Charblock 1, screenblock 16, 4bpp, 32x32 tiles.
+        let bg = BackgroundControl::from_bgcnt(0x1004);
+        let mut vram = vec![0u8; VRAM_SIZE];
+        
+        //Tile 1 is solid color 3.
+        for b in vram[0x4020..0x4040].iter_mut() {
+            *b = 0x33;
+        }
+        
+        //Place tile 1 at map cell (1, 0) using palette bank 2.
+        vram[0x8002] = 0x01;
+        vram[0x8003] = 0x20;
+        
+        let mut palette = vec![Rgba([0u8, 0, 0, 255]); 256];
+        palette[0x23] = Rgba([255, 0, 0, 255]);
+        
+        let scene = decode_vram_background_as_scene(&FormatRegistry::with_builtin_formats(), &vram, &bg, false, &palette).unwrap();
+        
+        assert_eq!(scene.dimensions(), (256, 256));
+        assert_eq!(*scene.get_pixel(8, 0), Rgba([255u8, 0, 0, 255]));
+        assert_eq!(*scene.get_pixel(0, 0), Rgba([0u8, 0, 0, 0]));
+    }
+}
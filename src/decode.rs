@@ -1,22 +1,77 @@
 extern crate argparse;
 extern crate image;
 extern crate num;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate toml;
 
 mod awsmimg;
 
 use argparse::{ArgumentParser, Store, StoreFalse, StoreTrue};
-use std::fs::{OpenOptions};
+use std::fs::{self, OpenOptions};
 use std::io;
-use std::io::Seek;
-use awsmimg::decoder::{decode_indexes_as_image_with_format};
-use awsmimg::formats::{interpret_indexed_format_name, interpret_direct_format_name};
+use std::process;
+use std::io::{Cursor, Read, Seek};
+use std::cmp::min;
+use awsmimg::decoder::{decode_indexes_as_image, decode_indexes_as_color_image, decode_tilemap_as_image, MapDecoder, decode_mapped_scene_as_image, decode_sprites_as_image, decode_sprites_as_color_image};
+use awsmimg::palette::{palette_format_option, read_palette_file_from, write_palette_file};
+use awsmimg::vram::{BackgroundControl, parse_register, object_tiles, decode_vram_background_as_image, decode_vram_background_as_scene, OBJ_FIRST_COLOR};
+use awsmimg::sprites::{parse_obj_size, interpret_obj_mapping_name};
+use awsmimg::oam::{parse_oam, obj_mapping_from_dispcnt, render_obj, render_oam};
+use awsmimg::bitmap::{interpret_bitmap_mode_name, decode_bitmap_as_image};
+use awsmimg::grid::{GridOptions, interpret_grid_label_name, annotate_grid};
+use awsmimg::formats::{interpret_map_format_name, IndexedGraphicsProperties};
+use awsmimg::formats::agbmap::AGBMapEncoder;
+use awsmimg::formats::layout::read_layout_file;
+use awsmimg::registry::FormatRegistry;
+use awsmimg::address::{parse_offset, parse_rom_offset, follow_pointer};
+use awsmimg::patch::{patch_format_option, read_patched_file};
+use awsmimg::{Error, Result};
 
-fn main() -> io::Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let mut input_filename = "".to_string();
     let mut output_filename = "".to_string();
     let mut format = "".to_string();
-    let mut offset = 0u64;
+    let mut offset = "".to_string();
+    let mut pointer = "".to_string();
     let mut size = u64::max_value();
+    let mut tiles = 0usize;
+    let mut width_tiles = 0u32;
+    let mut height_tiles = 0u32;
+    let mut grid_filename = "".to_string();
+    let mut grid_labels = "tiles".to_string();
+    let mut grid_scale = 4u32;
+    let mut palette_filename = "".to_string();
+    let mut palette_format = "".to_string();
+    let mut palette_offset = "0".to_string();
+    let mut palette_colors = 0usize;
+    let mut palette_out_filename = "".to_string();
+    let mut palette_out_format = "".to_string();
+    let mut map_filename = "".to_string();
+    let mut map_format = "agbtext".to_string();
+    let mut map_offset = "0".to_string();
+    let mut map_width = 32u32;
+    let mut map_height = 32u32;
+    let mut vram = false;
+    let mut bgcnt = "".to_string();
+    let mut affine = false;
+    let mut obj = false;
+    let mut obj_size = "".to_string();
+    let mut obj_mapping = "1d".to_string();
+    let mut oam_filename = "".to_string();
+    let mut dispcnt = "".to_string();
+    let mut obj_separate = false;
+    let mut list_formats = false;
+    let mut layouts_filename = "".to_string();
+    let mut patch_filename = "".to_string();
+    let mut patch_format = "".to_string();
 
     {
         let mut ap = ArgumentParser::new();
@@ -25,25 +80,267 @@ fn main() -> io::Result<()> {
 
         ap.refer(&mut input_filename).add_argument("input", Store, "The retro image data to decode.");
         ap.refer(&mut output_filename).add_argument("output", Store, "Where to store the modern image file.");
-        ap.refer(&mut format).add_option(&["--format"], Store, "The format to convert the image from.");
-        ap.refer(&mut offset).add_option(&["--offset"], Store, "Where to read data from within the source file.");
-        ap.refer(&mut size).add_option(&["--size"], Store, "Maximum amount of data to read from the file.");
+        ap.refer(&mut format).add_option(&["--format"], Store, "The format to convert the image from, or mode3, mode4, or mode5 for a framebuffer dump.");
+        ap.refer(&mut offset).add_option(&["--offset"], Store, "Where to read data from within the source file, in decimal or 0x-prefixed hex. GBA cartridge addresses such as 0x08123456 are translated to file offsets.");
+        ap.refer(&mut pointer).add_option(&["--pointer"], Store, "Read data from wherever the little-endian GBA pointer stored at this offset of the source file points, instead of --offset.");
+        ap.refer(&mut patch_filename).add_option(&["--patch"], Store, "Read the source file as changed by the IPS or BPS patch in this file, without changing either.");
+        ap.refer(&mut patch_format).add_option(&["--patch-format"], Store, "Format of the patch given by --patch: ips or bps. Chosen by file extension if not given.");
+        ap.refer(&mut size).add_option(&["--size"], Store, "Maximum amount of data to read from the file, in bytes.");
+        ap.refer(&mut tiles).add_option(&["--tiles"], Store, "Maximum number of tiles to decode.");
+        ap.refer(&mut width_tiles).add_option(&["--width-tiles"], Store, "Width of the decoded image, in tiles.");
+        ap.refer(&mut height_tiles).add_option(&["--height-tiles"], Store, "Height of the decoded image, in tiles.");
+        ap.refer(&mut grid_filename).add_option(&["--grid"], Store, "Also save a scaled-up copy of the image with a tile grid drawn over it, for reference only.");
+        ap.refer(&mut grid_labels).add_option(&["--grid-labels"], Store, "What to label each tile of the --grid image with: none, tiles, or offsets.");
+        ap.refer(&mut grid_scale).add_option(&["--grid-scale"], Store, "How many times larger than the decoded image the --grid image is.");
+        ap.refer(&mut palette_filename).add_option(&["--palette"], Store, "Color the decoded image using the palette in this file.");
+        ap.refer(&mut palette_format).add_option(&["--palette-format"], Store, "Format of the palette file: jasc, gimp, act, or raw. Guessed if not given.");
+        ap.refer(&mut palette_offset).add_option(&["--palette-offset"], Store, "Where to read a raw palette from within the palette file, in decimal or 0x-prefixed hex.");
+        ap.refer(&mut palette_colors).add_option(&["--palette-colors"], Store, "Number of colors to read from the palette file.");
+        ap.refer(&mut palette_out_filename).add_option(&["--palette-out"], Store, "Also save the palette to this file, e.g. to convert a ripped palette for a paint program.");
+        ap.refer(&mut palette_out_format).add_option(&["--palette-out-format"], Store, "Format of the saved palette file. Chosen by file extension if not given.");
+        ap.refer(&mut map_filename).add_option(&["--map"], Store, "Rebuild a full screen by placing the decoded tiles according to the tilemap in this file. Rendered in color if --palette is given.");
+        ap.refer(&mut map_format).add_option(&["--map-format"], Store, "The format of the tilemap: agbtext or agbaffine.");
+        ap.refer(&mut map_offset).add_option(&["--map-offset"], Store, "Where to read the tilemap from within the map file, in decimal or 0x-prefixed hex.");
+        ap.refer(&mut map_width).add_option(&["--map-width"], Store, "Width of the tilemap, in tiles.");
+        ap.refer(&mut map_height).add_option(&["--map-height"], Store, "Height of the tilemap, in tiles.");
+        ap.refer(&mut vram).add_option(&["--vram"], StoreTrue, "Treat the input as a raw AGB VRAM dump. Use --palette for a palette RAM dump.");
+        ap.refer(&mut bgcnt).add_option(&["--bgcnt"], Store, "Reconstruct the background with this BGxCNT register value from the VRAM dump.");
+        ap.refer(&mut affine).add_option(&["--affine"], StoreTrue, "The background given by --bgcnt is an affine background.");
+        ap.refer(&mut obj).add_option(&["--obj"], StoreTrue, "Decode the sprite tiles of the VRAM dump instead of a background.");
+        ap.refer(&mut obj_size).add_option(&["--obj-size"], Store, "Rebuild a sheet of sprite frames of this size, e.g. 32x16, from tiles in sprite order.");
+        ap.refer(&mut obj_mapping).add_option(&["--obj-mapping"], Store, "Sprite tile mapping mode used by --obj-size and --oam: 1d or 2d.");
+        ap.refer(&mut oam_filename).add_option(&["--oam"], Store, "Render the visible sprites described by this OAM dump, using the input as sprite tile memory.");
+        ap.refer(&mut dispcnt).add_option(&["--dispcnt"], Store, "DISPCNT register value to take the sprite mapping mode from, instead of --obj-mapping.");
+        ap.refer(&mut obj_separate).add_option(&["--obj-separate"], StoreTrue, "Save each visible sprite given by --oam as its own numbered image instead of compositing them.");
+        ap.refer(&mut layouts_filename).add_option(&["--layouts"], Store, "Load the tile layout descriptions in this TOML file, making them available to --format by name.");
+        ap.refer(&mut list_formats).add_option(&["--list-formats"], StoreTrue, "List the indexed formats --format accepts, then exit.");
 
         ap.parse_args_or_exit();
     }
 
+    let mut registry = FormatRegistry::with_builtin_formats();
+    if !layouts_filename.is_empty() {
+        for layout in read_layout_file(&layouts_filename)? {
+            registry.register_layout(layout);
+        }
+    }
+
+    if list_formats {
+        for (name, description) in registry.indexed_formats() {
+            println!("{:8} {}", name, description);
+        }
+
+        return Ok(());
+    }
+
     println!("Decoding {} to {}", input_filename, output_filename);
 
-    let mut bin = OpenOptions::new().read(true).open(input_filename)?;
+    let palette_offset = parse_offset(&palette_offset)?;
+    let map_offset = parse_offset(&map_offset)?;
+
+    //Sprite colors live in the second half of palette RAM.
+    let first_color = match vram && (obj || !oam_filename.is_empty()) {
+        true => OBJ_FIRST_COLOR,
+        false => 0
+    };
+
+    let palette = match palette_filename.is_empty() {
+        true => None,
+        false => Some(read_palette_file_from(&palette_filename, palette_format_option(&palette_format)?, palette_offset, first_color, palette_colors)?)
+    };
+
+    if let Some(ref pal) = palette {
+        if !palette_out_filename.is_empty() {
+            write_palette_file(&palette_out_filename, palette_format_option(&palette_out_format)?, pal)?;
+        }
+    }
+
+    let sprite_layout = match obj_size.is_empty() {
+        true => None,
+        false => match (parse_obj_size(&obj_size), interpret_obj_mapping_name(&obj_mapping)) {
+            (Some(size), Some(mapping)) => Some((size, mapping)),
+            (None, _) => return Err(Error::invalid("Sprite sizes must be given as WIDTHxHEIGHT.")),
+            (_, None) => return Err(Error::invalid("Unknown sprite mapping mode."))
+        }
+    };
+
+    if !grid_filename.is_empty() && (vram || !oam_filename.is_empty() || !map_filename.is_empty() || sprite_layout.is_some() || interpret_bitmap_mode_name(&format).is_some()) {
+        return Err(Error::invalid("--grid only applies to plain tile sheets, not tilemaps, sprites, VRAM dumps, or bitmaps."));
+    }
+
+    let contents = match patch_filename.is_empty() {
+        true => fs::read(&input_filename)?,
+        false => read_patched_file(&input_filename, &patch_filename, patch_format_option(&patch_format, &patch_filename)?)?
+    };
+    let mut bin = Cursor::new(contents);
+    let offset = match (pointer.is_empty(), offset.is_empty()) {
+        (true, true) => 0,
+        (true, false) => parse_rom_offset(&offset)?,
+        (false, true) => follow_pointer(&mut bin, parse_rom_offset(&pointer)?)?,
+        (false, false) => return Err(Error::invalid("--pointer and --offset cannot be combined."))
+    };
+
+    if !oam_filename.is_empty() {
+        let pal = match palette {
+            Some(pal) => pal,
+            None => return Err(Error::invalid("Rendering sprites requires a --palette."))
+        };
+        let mapping = match (dispcnt.is_empty(), parse_register(&dispcnt), interpret_obj_mapping_name(&obj_mapping)) {
+            (false, Some(value), _) => obj_mapping_from_dispcnt(value),
+            (false, None, _) => return Err(Error::invalid("Invalid --dispcnt value.")),
+            (true, _, Some(mapping)) => mapping,
+            (true, _, None) => return Err(Error::invalid("Unknown sprite mapping mode."))
+        };
+
+        let mut oam_data = Vec::new();
+        OpenOptions::new().read(true).open(oam_filename)?.read_to_end(&mut oam_data)?;
+        let oam = parse_oam(&oam_data)?;
+
+        let mut dump = Vec::new();
+        bin.read_to_end(&mut dump)?;
+        let obj_vram = match vram {
+            true => object_tiles(&dump)?,
+            false => &dump[..]
+        };
+
+        if !obj_separate {
+            return Ok(render_oam(obj_vram, &oam, &pal, mapping)?.save(output_filename)?);
+        }
+
+        let stem = output_filename.trim_end_matches(".png");
+        for (i, o) in oam.iter().enumerate().filter(|&(_, o)| o.visible()) {
+            render_obj(obj_vram, o, &pal, mapping)?.save(format!("{}-{:03}.png", stem, i))?;
+        }
+
+        return Ok(());
+    }
+
+    if vram {
+        let mut dump = Vec::new();
+        bin.read_to_end(&mut dump)?;
+
+        if obj {
+            let obj_data = object_tiles(&dump)?;
+            let obj_len = obj_data.len();
+            let mut obj_reader = Cursor::new(obj_data);
+            let name = match format.is_empty() {
+                true => "agb4",
+                false => &format
+            };
+            let mut dec = registry.indexed_decoder(name, &mut obj_reader)?;
+
+            let (pixels, imgsize) = sheet_size(&dec, obj_len, tiles, width_tiles, height_tiles);
+
+            return Ok(match (palette, sprite_layout) {
+                (Some(pal), Some((size, mapping))) => decode_sprites_as_color_image(&mut dec, pixels, size, mapping, &pal)?.save(output_filename)?,
+                (None, Some((size, mapping))) => decode_sprites_as_image(&mut dec, pixels, size, mapping)?.save(output_filename)?,
+                (Some(pal), None) => decode_indexes_as_color_image(&mut dec, pixels, imgsize, &pal)?.save(output_filename)?,
+                (None, None) => decode_indexes_as_image(&mut dec, pixels, imgsize)?.save(output_filename)?
+            });
+        }
+
+        let bg = match parse_register(&bgcnt) {
+            Some(value) => BackgroundControl::from_bgcnt(value),
+            None => return Err(Error::invalid("VRAM dumps require --obj or a valid --bgcnt value."))
+        };
+
+        return Ok(match palette {
+            Some(pal) => decode_vram_background_as_scene(&registry, &dump, &bg, affine, &pal)?.save(output_filename)?,
+            None => decode_vram_background_as_image(&registry, &dump, &bg, affine)?.save(output_filename)?
+        });
+    }
+
     let orig_length = bin.seek(io::SeekFrom::End(0))?;
     if offset > orig_length {
         //Seeking beyond the end of a file is implementation defined. Hence, we error out
-        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proposed offset length exceeds length of file."))
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proposed offset length exceeds length of file.").into())
     }
     bin.seek(io::SeekFrom::Start(offset))?;
 
-    let idxfmt = interpret_indexed_format_name(&format).unwrap();
-    let img = decode_indexes_as_image_with_format(idxfmt, &mut bin, size as usize, None)?;
+    //Never try to decode more data than the file actually holds.
+    let size = min(size, orig_length - offset);
+
+    if let Some(mode) = interpret_bitmap_mode_name(&format) {
+        return Ok(decode_bitmap_as_image(mode, &mut bin, size as usize, palette.as_ref().map(|p| &p[..]))?.save(output_filename)?);
+    }
+
+    let mut dec = registry.indexed_decoder(&format, &mut bin)?;
+    let (pixels, imgsize) = sheet_size(&dec, size as usize, tiles, width_tiles, height_tiles);
+    let grid = match (grid_filename.is_empty(), interpret_grid_label_name(&grid_labels)) {
+        (true, _) => None,
+        (false, Some(labels)) => {
+            let (tw, th) = dec.tile_size();
+            Some(GridOptions { tsize: (tw, th), scale: grid_scale, labels, base_offset: offset, tile_bytes: (tw * th * dec.bits_per_pixel() / 8) as u64 })
+        },
+        (false, None) => return Err(Error::invalid("Unknown grid label kind."))
+    };
+
+    if !map_filename.is_empty() {
+        let mapfmt = match interpret_map_format_name(&map_format) {
+            Some(mapfmt) => mapfmt,
+            None => return Err(Error::UnknownFormat(map_format))
+        };
+
+        let mut mapfile = OpenOptions::new().read(true).open(map_filename)?;
+        mapfile.seek(io::SeekFrom::Start(map_offset))?;
+
+        let map = AGBMapEncoder::new(&mut mapfile, mapfmt).decode_map(map_width, map_height)?;
+
+        return Ok(match palette {
+            Some(pal) => decode_mapped_scene_as_image(&mut dec, pixels, &map, (map_width, map_height), &pal)?.save(output_filename)?,
+            None => decode_tilemap_as_image(&mut dec, pixels, &map, (map_width, map_height))?.save(output_filename)?
+        });
+    }
+
+    match (palette, sprite_layout) {
+        (Some(pal), Some((obj_size, mapping))) => decode_sprites_as_color_image(&mut dec, pixels, obj_size, mapping, &pal)?.save(output_filename)?,
+        (None, Some((obj_size, mapping))) => decode_sprites_as_image(&mut dec, pixels, obj_size, mapping)?.save(output_filename)?,
+        (Some(pal), None) => {
+            let img = decode_indexes_as_color_image(&mut dec, pixels, imgsize, &pal)?;
+            if let Some(ref options) = grid {
+                annotate_grid(&*img, options)?.save(&grid_filename)?;
+            }
+            img.save(output_filename)?
+        },
+        (None, None) => {
+            let img = decode_indexes_as_image(&mut dec, pixels, imgsize)?;
+            if let Some(ref options) = grid {
+                annotate_grid(&*img, options)?.save(&grid_filename)?;
+            }
+            img.save(output_filename)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Determine how many pixels to decode from an amount of data, and the size of
+/// the image to decode them into, given the --tiles, --width-tiles, and
+/// --height-tiles options. Zero means an option was not given.
+///
+/// Only whole tiles are ever decoded. If only one image dimension is given,
+/// the other is chosen to fit every decoded tile.
+fn sheet_size<F>(format: &F, bytes: usize, tiles: usize, width_tiles: u32, height_tiles: u32) -> (usize, Option<(u32, u32)>) where F: IndexedGraphicsProperties + ?Sized {
+    let (tw, th) = format.tile_size();
+    let tile_pixels = (tw * th) as usize;
+    let mut pixels = bytes.saturating_mul(8) / format.bits_per_pixel() as usize;
+
+    let tiles = match tiles {
+        0 => width_tiles as usize * height_tiles as usize,
+        t => t
+    };
+    if tiles > 0 {
+        pixels = min(pixels, tiles.saturating_mul(tile_pixels));
+    }
+    pixels -= pixels % tile_pixels;
+
+    let tcount = (pixels / tile_pixels) as u32;
+    let imgsize = match (width_tiles, height_tiles) {
+        (0, 0) => None,
+        (w, 0) => Some((w * tw, tcount.div_ceil(w) * th)),
+        (0, h) => Some((tcount.div_ceil(h) * tw, h * th)),
+        (w, h) => Some((w * tw, h * th))
+    };
 
-    img.save(output_filename)
+    (pixels, imgsize)
 }
@@ -1,22 +1,80 @@
 extern crate argparse;
 extern crate image;
 extern crate num;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate toml;
 
 mod awsmimg;
 
 use argparse::{ArgumentParser, Store, StoreFalse, StoreTrue};
-use std::fs::{OpenOptions};
+use std::fs::{self, OpenOptions};
 use std::io;
-use std::io::Seek;
-use awsmimg::encoder::{encode_image_as_indexes_with_format, encode_image_as_direct_color_with_format};
-use awsmimg::formats::{interpret_indexed_format_name, interpret_direct_format_name};
+use std::process;
+use std::io::{Cursor, Read, Seek, Write};
+use std::cmp::min;
+use std::ops::Range;
+use awsmimg::encoder::{encode_image_as_indexes, encode_image_as_palette_indexes, encode_image_as_quantized_indexes, encode_image_as_subpalette_indexes, encode_image_as_direct_color_with_format, encode_image_as_tilemap, encode_image_as_sprites, IndexSource};
+use awsmimg::palette::{palette_format_option, read_palette_file, write_palette_file};
+use awsmimg::dither::{Dither, interpret_dither_name, dither_to_bgr555};
+use awsmimg::sprites::{parse_obj_size, interpret_obj_mapping_name};
+use awsmimg::bitmap::{interpret_bitmap_mode_name, encode_image_as_bitmap};
+use awsmimg::formats::{interpret_direct_format_name, interpret_map_format_name};
+use awsmimg::formats::layout::read_layout_file;
+use awsmimg::formats::agb::AGB8Encoder;
+use awsmimg::formats::agbmap::AGBMapEncoder;
+use awsmimg::registry::FormatRegistry;
+use awsmimg::validate::validate_image;
+use awsmimg::verify::{RecordingEncoder, verify_indexes};
+use awsmimg::formats::IndexedGraphicsProperties;
+use awsmimg::conversion::visible_tiles;
+use awsmimg::tiles::visible_spans;
+use awsmimg::address::{parse_offset, parse_rom_offset, follow_pointer};
+use awsmimg::checksum::{RomKind, interpret_rom_kind_name, check_checksums, fix_checksums};
+use awsmimg::patch::{PatchFormat, patch_format_option, read_patched_file, create_patch, apply_patch};
+use awsmimg::{Error, Result};
 
-fn main() -> io::Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let mut input_filename = "".to_string();
     let mut output_filename = "".to_string();
     let mut format = "".to_string();
     let mut truncatemode = true;
-    let mut offset = 0u64;
+    let mut offset = "".to_string();
+    let mut pointer = "".to_string();
+    let mut palette_filename = "".to_string();
+    let mut palette_format = "".to_string();
+    let mut palette_offset = "0".to_string();
+    let mut palette_colors = 0usize;
+    let mut nearest = false;
+    let mut quantize = false;
+    let mut palette_out_filename = "".to_string();
+    let mut palette_out_format = "".to_string();
+    let mut subpalettes = 0usize;
+    let mut banks_out_filename = "".to_string();
+    let mut dither_name = "none".to_string();
+    let mut map_out_filename = "".to_string();
+    let mut map_format = "agbtext".to_string();
+    let mut obj_size = "".to_string();
+    let mut obj_mapping = "1d".to_string();
+    let mut list_formats = false;
+    let mut layouts_filename = "".to_string();
+    let mut strict = false;
+    let mut verify = false;
+    let mut max_size = u64::MAX;
+    let mut end_offset = "".to_string();
+    let mut sparse = false;
+    let mut patch_out_filename = "".to_string();
+    let mut patch_format = "".to_string();
+    let mut apply_patch_filename = "".to_string();
+    let mut fix_checksum = "".to_string();
+    let mut check_checksum = "".to_string();
 
     {
         let mut ap = ArgumentParser::new();
@@ -25,33 +83,409 @@ fn main() -> io::Result<()> {
 
         ap.refer(&mut input_filename).add_argument("input", Store, "Name of the modern image file to convert.");
         ap.refer(&mut output_filename).add_argument("output", Store, "Where to store the converted image as.");
-        ap.refer(&mut format).add_option(&["--format"], Store, "The format to convert the image into.");
+        ap.refer(&mut format).add_option(&["--format"], Store, "The format to convert the image into, or mode3, mode4, or mode5 for a framebuffer. Framebuffer images may stack two frames vertically for both pages.");
         ap.refer(&mut truncatemode).add_option(&["--overlay"], StoreFalse, "Overlay encoding result onto existing file. Negates --truncate.")
                                    .add_option(&["--truncate"], StoreTrue, "Erases existing file (if any) before encoding. Negates --overlay.");
-        ap.refer(&mut offset).add_option(&["--offset"], Store, "Where to write data to within the target file.");
+        ap.refer(&mut sparse).add_option(&["--sparse"], StoreTrue, "Skip fully transparent tiles, leaving their bytes in the target file untouched. Implies --overlay.");
+        ap.refer(&mut patch_out_filename).add_option(&["--patch-out"], Store, "Leave the target file untouched and store the changes to it as a patch here instead. Changes already in this patch are kept. Implies --overlay.");
+        ap.refer(&mut patch_format).add_option(&["--patch-format"], Store, "Format of the patch used by --patch-out or --apply-patch: ips or bps. Chosen by file extension if not given.");
+        ap.refer(&mut apply_patch_filename).add_option(&["--apply-patch"], Store, "Apply the IPS or BPS patch in this file to the input file and store the patched file as the output, then exit.");
+        ap.refer(&mut fix_checksum).add_option(&["--fix-checksum"], Store, "After writing, recompute the header checksums of the target file as a ROM of this kind: gba, gb, or nds.");
+        ap.refer(&mut check_checksum).add_option(&["--check-checksum"], Store, "Report whether the header checksums of the ROM given as the only file, of this kind (gba, gb, or nds), are valid, then exit.");
+        ap.refer(&mut offset).add_option(&["--offset"], Store, "Where to write data to within the target file, in decimal or 0x-prefixed hex. GBA cartridge addresses such as 0x08123456 are translated to file offsets.");
+        ap.refer(&mut pointer).add_option(&["--pointer"], Store, "Write data to wherever the little-endian GBA pointer stored at this offset of the target file points, instead of --offset.");
+        ap.refer(&mut max_size).add_option(&["--max-size"], Store, "Fail, leaving the target file untouched, if the encoded data is larger than this many bytes.");
+        ap.refer(&mut end_offset).add_option(&["--end-offset"], Store, "Fail, leaving the target file untouched, if the encoded data would extend past this offset within the target file. Accepts the same notation as --offset.");
+        ap.refer(&mut palette_filename).add_option(&["--palette"], Store, "Map image colors to indexes using the palette in this file.");
+        ap.refer(&mut palette_format).add_option(&["--palette-format"], Store, "Format of the palette file: jasc, gimp, act, or raw. Guessed if not given.");
+        ap.refer(&mut palette_offset).add_option(&["--palette-offset"], Store, "Where to read a raw palette from within the palette file, in decimal or 0x-prefixed hex.");
+        ap.refer(&mut palette_colors).add_option(&["--palette-colors"], Store, "Number of colors to read from the palette file.");
+        ap.refer(&mut nearest).add_option(&["--nearest"], StoreTrue, "Map colors not in the palette to the nearest palette color instead of failing.");
+        ap.refer(&mut quantize).add_option(&["--quantize"], StoreTrue, "Reduce a truecolor image to the number of colors the format supports.");
+        ap.refer(&mut palette_out_filename).add_option(&["--palette-out"], Store, "Where to store the palette generated by --quantize or --subpalettes. Required by both.");
+        ap.refer(&mut palette_out_format).add_option(&["--palette-out-format"], Store, "Format of the generated palette file. Chosen by file extension if not given.");
+        ap.refer(&mut subpalettes).add_option(&["--subpalettes"], Store, "Reduce a truecolor image to at most this many sub-palettes, one per attribute region.");
+        ap.refer(&mut banks_out_filename).add_option(&["--banks-out"], Store, "Where to store the sub-palette bank number of each attribute region.");
+        ap.refer(&mut dither_name).add_option(&["--dither"], Store, "Dithering to use when reducing colors: none, bayer, floyd-steinberg, or tile.");
+        ap.refer(&mut map_out_filename).add_option(&["--map-out"], Store, "Deduplicate the image's tiles and store a tilemap that reassembles it here.");
+        ap.refer(&mut map_format).add_option(&["--map-format"], Store, "The format of the tilemap written by --map-out: agbtext or agbaffine.");
+        ap.refer(&mut obj_size).add_option(&["--obj-size"], Store, "Treat the image as a sheet of sprite frames of this size, e.g. 32x16, and store each frame's tiles in sprite order.");
+        ap.refer(&mut obj_mapping).add_option(&["--obj-mapping"], Store, "Sprite tile mapping mode used by --obj-size: 1d or 2d.");
+        ap.refer(&mut layouts_filename).add_option(&["--layouts"], Store, "Load the tile layout descriptions in this TOML file, making them available to --format by name.");
+        ap.refer(&mut strict).add_option(&["--strict"], StoreTrue, "Check the image against every constraint of the format and list all problems found, instead of writing anything, if there are any.");
+        ap.refer(&mut verify).add_option(&["--verify"], StoreTrue, "After writing, read the written data back and report any tiles that do not match the image.");
+        ap.refer(&mut list_formats).add_option(&["--list-formats"], StoreTrue, "List the indexed formats --format accepts, then exit.");
 
         ap.parse_args_or_exit();
     }
 
+    let mut registry = FormatRegistry::with_builtin_formats();
+    if !layouts_filename.is_empty() {
+        for layout in read_layout_file(&layouts_filename)? {
+            registry.register_layout(layout);
+        }
+    }
+
+    if list_formats {
+        for (name, description) in registry.indexed_formats() {
+            println!("{:8} {}", name, description);
+        }
+
+        return Ok(());
+    }
+
+    if !apply_patch_filename.is_empty() {
+        println!("Patching {} to {}", input_filename, output_filename);
+
+        let patched = read_patched_file(&input_filename, &apply_patch_filename, patch_format_option(&patch_format, &apply_patch_filename)?)?;
+        return Ok(fs::write(&output_filename, patched)?);
+    }
+
+    if !check_checksum.is_empty() {
+        let kind = match interpret_rom_kind_name(&check_checksum) {
+            Some(kind) => kind,
+            None => return Err(Error::UnknownFormat(check_checksum))
+        };
+        let rom = fs::read(&input_filename)?;
+        let checks = check_checksums(kind, &rom)?;
+
+        for check in checks.iter() {
+            println!("{}", check);
+        }
+
+        return match checks.iter().find(|check| !check.is_valid()) {
+            Some(check) => Err(Error::CorruptData { offset: check.offset as u64, reason: format!("{} has bad checksums", input_filename) }),
+            None => Ok(())
+        };
+    }
+
     println!("Converting {} to {}", input_filename, output_filename);
 
-    let mut bin = OpenOptions::new().write(true).create(true).truncate(truncatemode).open(output_filename)?;
+    if sparse || !patch_out_filename.is_empty() {
+        truncatemode = false;
+    }
+
+    let bitmap = interpret_bitmap_mode_name(&format);
+    let indexed = registry.contains_indexed(&format) || bitmap.map_or(false, |mode| mode.is_indexed());
+    let dither = match interpret_dither_name(&dither_name) {
+        Some(d) => d,
+        None => return Err(Error::invalid("Unknown dithering method."))
+    };
+    if (quantize || subpalettes > 0) && (!indexed || !palette_filename.is_empty()) {
+        return Err(Error::invalid("Quantization requires an indexed format and cannot be combined with --palette."));
+    }
+
+    //The indexes of a generated palette mean nothing without the palette.
+    if (quantize || subpalettes > 0) && palette_out_filename.is_empty() {
+        return Err(Error::invalid("Quantization requires --palette-out to store the generated palette."));
+    }
+
+    //Indexed formats only dither while quantizing; mapping through a palette,
+    //grayscale and sub-palette reduction have nowhere to take the error.
+    if dither != Dither::None && indexed && (!quantize || subpalettes > 0) {
+        return Err(Error::invalid("Dithering an indexed format requires --quantize and cannot be combined with --subpalettes."));
+    }
+
+    if verify && (bitmap.is_some() || !indexed) {
+        return Err(Error::invalid("Verification requires an indexed tile format."));
+    }
+
+    if sparse && (bitmap.is_some() || !indexed || !obj_size.is_empty() || !map_out_filename.is_empty()) {
+        return Err(Error::invalid("Sparse overlays require an indexed tile format and cannot be combined with sprites or tilemaps."));
+    }
+
+    let offset = match (pointer.is_empty(), offset.is_empty()) {
+        (true, true) => 0,
+        (true, false) => parse_rom_offset(&offset)?,
+        (false, true) => follow_pointer(&mut OpenOptions::new().read(true).open(&output_filename)?, parse_rom_offset(&pointer)?)?,
+        (false, false) => return Err(Error::invalid("--pointer and --offset cannot be combined."))
+    };
+    let end_offset = match end_offset.is_empty() {
+        true => u64::MAX,
+        false => parse_rom_offset(&end_offset)?
+    };
+
+    if end_offset < offset {
+        return Err(Error::invalid("The end offset must not come before the offset being written to."));
+    }
+    let limit = min(max_size, end_offset - offset);
+
+    let fix = match fix_checksum.is_empty() {
+        true => None,
+        false => match interpret_rom_kind_name(&fix_checksum) {
+            Some(kind) => Some(kind),
+            None => return Err(Error::UnknownFormat(fix_checksum))
+        }
+    };
+
+    let target = match patch_out_filename.is_empty() {
+        true => Target::File { filename: output_filename.clone(), truncate: truncatemode },
+        false => {
+            let format = patch_format_option(&patch_format, &patch_out_filename)?;
+
+            Target::Patch { original: output_filename.clone(), filename: patch_out_filename, format }
+        }
+    };
+
+    let palette_out_format = palette_format_option(&palette_out_format)?;
+    let palette = match (indexed, palette_filename.is_empty()) {
+        (_, true) => None,
+        (true, false) => Some(read_palette_file(&palette_filename, palette_format_option(&palette_format)?, parse_offset(&palette_offset)?, palette_colors)?),
+        (false, false) => return Err(Error::invalid("Palette mapping requires an indexed format."))
+    };
+
+    let img = image::open(input_filename)?;
+    let source = match palette {
+        Some(ref pal) => IndexSource::Palette(pal, nearest),
+        None if subpalettes > 0 => IndexSource::Subpalettes(subpalettes),
+        None if quantize => IndexSource::Quantize(dither),
+        None => IndexSource::Luma
+    };
+
+    //Strict checks happen before anything is encoded, as that is the point
+    //where the output would otherwise be written.
+    if strict {
+        let mut sink = io::sink();
+        let violations = match bitmap {
+            Some(mode) if mode.is_indexed() => validate_image(&AGB8Encoder::new_chunky(&mut sink), &img, &source),
+            None if indexed => validate_image(&registry.indexed_encoder(&format, &mut sink)?, &img, &source),
+            _ => Vec::new()
+        };
+
+        if !violations.is_empty() {
+            for violation in violations.iter() {
+                eprintln!("{}", violation);
+            }
+
+            return Err(Error::invalid(format!("Found {} problems with the image; nothing was written.", violations.len())));
+        }
+    }
+
+    //Everything is encoded into memory first, so that data that does not fit
+    //can be rejected without touching the target file.
+    let mut data = Vec::new();
+
+    if let Some(mode) = bitmap {
+        let generated = match (mode.is_indexed(), dither) {
+            (false, Dither::None) | (true, _) => encode_image_as_bitmap(mode, &mut data, &img, source)?,
+            (false, _) => encode_image_as_bitmap(mode, &mut data, &dither_to_bgr555(&img, dither), source)?
+        };
+
+        write_output(&target, offset, &data, limit, None, fix)?;
+
+        if let (Some(pal), false) = (generated, palette_out_filename.is_empty()) {
+            write_palette_file(&palette_out_filename, palette_out_format, &pal)?;
+        }
+
+        return Ok(());
+    }
+
+    if !indexed {
+        if !obj_size.is_empty() || !map_out_filename.is_empty() {
+            return Err(Error::invalid("Sprites and tilemaps require an indexed format."));
+        }
+
+        let dirfmt = match interpret_direct_format_name(&format) {
+            Some(dirfmt) => dirfmt,
+            None => return Err(Error::UnknownFormat(format))
+        };
+
+        match dither {
+            Dither::None => encode_image_as_direct_color_with_format(dirfmt, &mut data, &img)?,
+            _ => encode_image_as_direct_color_with_format(dirfmt, &mut data, &dither_to_bgr555(&img, dither))?
+        };
+
+        write_output(&target, offset, &data, limit, None, fix)?;
+        return Ok(());
+    }
+
+    let mut enc = RecordingEncoder::new(registry.indexed_encoder(&format, &mut data)?);
+    let tsize = enc.tile_size();
+    let tile_bits = tsize.0 * tsize.1 * enc.bits_per_pixel();
+    let visible = match sparse {
+        true if !tile_bits.is_multiple_of(8) => return Err(Error::invalid("Sparse overlays require a format whose tiles are a whole number of bytes.")),
+        true => Some(visible_tiles(&img, tsize)),
+        false => None
+    };
+
+    //The tilemap and bank numbers are held back with the tiles, so that
+    //nothing is written if the tiles do not fit.
+    let mut map_data = Vec::new();
+    let mut banks = None;
+
+    let generated = if !obj_size.is_empty() {
+        let size = match parse_obj_size(&obj_size) {
+            Some(size) => size,
+            None => return Err(Error::invalid("Sprite sizes must be given as WIDTHxHEIGHT."))
+        };
+        let mapping = match interpret_obj_mapping_name(&obj_mapping) {
+            Some(mapping) => mapping,
+            None => return Err(Error::invalid("Unknown sprite mapping mode."))
+        };
+
+        encode_image_as_sprites(&mut enc, &img, source, size, mapping)?
+    } else if !map_out_filename.is_empty() {
+        let mapfmt = match interpret_map_format_name(&map_format) {
+            Some(mapfmt) => mapfmt,
+            None => return Err(Error::UnknownFormat(map_format))
+        };
+
+        encode_image_as_tilemap(&mut enc, &mut AGBMapEncoder::new(&mut map_data, mapfmt), &img, source)?
+    } else {
+        match palette {
+            None if subpalettes > 0 => {
+                let assignment = encode_image_as_subpalette_indexes(&mut enc, &img, subpalettes)?;
+                let palette = assignment.flattened_palette();
+                banks = Some(assignment.banks);
+
+                Some(palette)
+            },
+            None if quantize => Some(encode_image_as_quantized_indexes(&mut enc, &img, dither)?),
+            Some(ref pal) => {
+                encode_image_as_palette_indexes(&mut enc, &img, pal, nearest)?;
+                None
+            },
+            None => {
+                encode_image_as_indexes(&mut enc, &img)?;
+                None
+            }
+        }
+    };
+
+    let expected = enc.into_indexes();
+    let spans = visible.as_ref().map(|visible| visible_spans(visible, (tile_bits / 8) as usize, data.len()));
+    let patched = write_output(&target, offset, &data, limit, spans.as_deref(), fix)?;
+
+    if !map_out_filename.is_empty() {
+        let mut mapfile = OpenOptions::new().write(true).create(true).truncate(true).open(map_out_filename)?;
+        mapfile.write_all(&map_data)?;
+    }
+
+    if let (Some(banks), false) = (banks, banks_out_filename.is_empty()) {
+        let mut banksfile = OpenOptions::new().write(true).create(true).truncate(true).open(banks_out_filename)?;
+        banksfile.write_all(&banks)?;
+    }
+
+    if let (Some(pal), false) = (generated, palette_out_filename.is_empty()) {
+        write_palette_file(&palette_out_filename, palette_out_format, &pal)?;
+    }
+
+    if verify {
+        match patched {
+            Some(contents) => verify_output(&registry, &format, &mut Cursor::new(contents), offset, &expected, visible.as_deref())?,
+            None => verify_output(&registry, &format, &mut OpenOptions::new().read(true).open(&output_filename)?, offset, &expected, visible.as_deref())?
+        }
+    }
+
+    Ok(())
+}
+
+/// Where encoded data ends up.
+enum Target {
+    /// Written straight into this file.
+    File {
+        filename: String,
+        truncate: bool
+    },
+
+    /// Written into a copy of the original file, as changed by any existing
+    /// patch at filename, then stored as a patch against the original.
+    Patch {
+        original: String,
+        filename: String,
+        format: PatchFormat
+    }
+}
+
+/// Write encoded data into the target at the given offset, unless there is
+/// more of it than limit allows, in which case the target is left alone.
+///
+/// If spans are given, only those ranges of the data are written, each at its
+/// own place relative to the offset. If fix is given, the ROM header
+/// checksums of that kind are recomputed afterwards. Patch targets return the
+/// patched file, since it exists nowhere else.
+fn write_output(target: &Target, offset: u64, data: &[u8], limit: u64, spans: Option<&[Range<usize>]>, fix: Option<RomKind>) -> Result<Option<Vec<u8>>> {
+    let size = data.len() as u64;
+    if size > limit {
+        return Err(Error::TooLarge { size, limit });
+    }
+
+    match *target {
+        Target::File { ref filename, truncate } => {
+            let mut bin = OpenOptions::new().read(true).write(true).create(true).truncate(truncate).open(filename)?;
+            write_at(&mut bin, offset, data, spans, fix)?;
+
+            Ok(None)
+        },
+        Target::Patch { ref original, ref filename, format } => {
+            let source = fs::read(original)?;
+            let current = match fs::read(filename) {
+                Ok(existing) => apply_patch(format, &source, &existing)?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => source.clone(),
+                Err(e) => return Err(e.into())
+            };
+
+            let mut bin = Cursor::new(current);
+            write_at(&mut bin, offset, data, spans, fix)?;
+            let patched = bin.into_inner();
+
+            fs::write(filename, create_patch(format, &source, &patched)?)?;
+            Ok(Some(patched))
+        }
+    }
+}
+
+fn write_at<W>(bin: &mut W, offset: u64, data: &[u8], spans: Option<&[Range<usize>]>, fix: Option<RomKind>) -> Result<()> where W: Read + Write + Seek {
     let orig_length = bin.seek(io::SeekFrom::End(0))?;
     if offset > orig_length {
         //Seeking beyond the end of a file is implementation defined. Hence, we error out
-        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proposed offset length exceeds length of file."))
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proposed offset length exceeds length of file.").into())
     }
     bin.seek(io::SeekFrom::Start(offset))?;
 
-    let img = image::open(input_filename).unwrap();
-    let idxfmt = interpret_indexed_format_name(&format);
+    match spans {
+        None => bin.write_all(data)?,
+        Some(spans) => for span in spans.iter() {
+            bin.seek(io::SeekFrom::Start(offset + span.start as u64))?;
+            bin.write_all(&data[span.clone()])?;
+        }
+    }
 
-    match idxfmt {
-        Some(fmt) => encode_image_as_indexes_with_format(fmt, &mut bin, &img),
-        None => {
-            let dirfmt = interpret_direct_format_name(&format).unwrap();
+    if let Some(kind) = fix {
+        let mut rom = Vec::new();
+        bin.seek(io::SeekFrom::Start(0))?;
+        bin.read_to_end(&mut rom)?;
 
-            encode_image_as_direct_color_with_format(dirfmt, &mut bin, &img)
+        for check in fix_checksums(kind, &mut rom)?.iter().filter(|check| !check.is_valid()) {
+            println!("{} (fixed)", check);
         }
+
+        bin.seek(io::SeekFrom::Start(0))?;
+        bin.write_all(&rom)?;
+    }
+
+    Ok(())
+}
+
+/// Read back the region of the output that was just written and check that it
+/// decodes to the indexes that were encoded, reporting each tile that does
+/// not. Tiles not marked visible were never written and are not checked.
+fn verify_output<R>(registry: &FormatRegistry, format: &str, file: &mut R, offset: u64, expected: &[u8], visible: Option<&[bool]>) -> Result<()> where R: Read + Seek {
+    file.seek(io::SeekFrom::Start(offset))?;
+
+    let mut dec = registry.indexed_decoder(format, file)?;
+    let (tw, th) = dec.tile_size();
+    let mut mismatches = verify_indexes(&mut dec, expected)?;
+    mismatches.retain(|mismatch| visible.map_or(true, |visible| visible.get(mismatch.tile).cloned().unwrap_or(true)));
+
+    let first = match mismatches.first() {
+        Some(mismatch) => offset + mismatch.offset,
+        None => return Ok(())
+    };
+
+    for mismatch in mismatches.iter() {
+        eprintln!("Tile {} at offset 0x{:X} has {} of {} pixels wrong", mismatch.tile, offset + mismatch.offset, mismatch.pixels, tw * th);
     }
+
+    Err(Error::CorruptData { offset: first, reason: format!("{} tiles did not read back as they were written", mismatches.len()) })
 }
@@ -1,4 +1,9 @@
 extern crate image;
 extern crate num;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate toml;
 
-pub mod awsmimg;
\ No newline at end of file
+pub mod awsmimg;
+
+pub use awsmimg::{Error, Result};